@@ -0,0 +1,152 @@
+use crate::prelude::*;
+use std::ops::{Add, Deref, Div, Mul, Neg, Sub};
+
+/// An `Operation` whose arithmetic operators simplify the result as it is built.
+///
+/// `Expr::var("x") * 2 + 3` gives `2 * x + 3` without calling `simplify`. Plain numbers,
+/// `f64`, `i32` or `i64`, can be used on the right hand side of every operator.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr(pub Operation);
+
+impl Expr {
+    /// Creates a symbol, as a `Text` operation.
+    pub fn var(name: &str) -> Expr {
+        Expr(Text(name.to_string()))
+    }
+
+    /// Creates a number, as a `Value` operation.
+    pub fn constant(value: f64) -> Expr {
+        Expr(Value(value))
+    }
+
+    pub fn into_inner(self) -> Operation {
+        self.0
+    }
+
+    /// Nested sums and products are merged first so chained constants fold together.
+    fn simplified(op: Operation) -> Expr {
+        Expr(op.flatten_associative().simplify_fully())
+    }
+}
+
+impl Deref for Expr {
+    type Target = Operation;
+
+    fn deref(&self) -> &Operation {
+        &self.0
+    }
+}
+
+impl From<Operation> for Expr {
+    fn from(op: Operation) -> Expr {
+        Expr(op)
+    }
+}
+
+impl From<f64> for Expr {
+    fn from(value: f64) -> Expr {
+        Expr(Value(value))
+    }
+}
+
+impl From<i32> for Expr {
+    fn from(value: i32) -> Expr {
+        Expr(Value(value.into()))
+    }
+}
+
+/// Integers beyond 2^53 in magnitude are rounded to the nearest `f64`.
+impl From<i64> for Expr {
+    fn from(value: i64) -> Expr {
+        Expr(Value(value as f64))
+    }
+}
+
+impl<T: Into<Expr>> Add<T> for Expr {
+    type Output = Expr;
+
+    fn add(self, rhs: T) -> Expr {
+        Expr::simplified(Sum(vec![self.0, rhs.into().0]))
+    }
+}
+
+impl<T: Into<Expr>> Sub<T> for Expr {
+    type Output = Expr;
+
+    fn sub(self, rhs: T) -> Expr {
+        Expr::simplified(Sum(vec![self.0, Negate(Some(Box::new(rhs.into().0)))]))
+    }
+}
+
+impl<T: Into<Expr>> Mul<T> for Expr {
+    type Output = Expr;
+
+    fn mul(self, rhs: T) -> Expr {
+        Expr::simplified(Multiply(vec![self.0, rhs.into().0]))
+    }
+}
+
+impl<T: Into<Expr>> Div<T> for Expr {
+    type Output = Expr;
+
+    fn div(self, rhs: T) -> Expr {
+        Expr::simplified(Divide(Some(Box::new(self.0)), Some(Box::new(rhs.into().0))))
+    }
+}
+
+impl Neg for Expr {
+    type Output = Expr;
+
+    fn neg(self) -> Expr {
+        Expr::simplified(Negate(Some(Box::new(self.0))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expr::Expr;
+    use crate::math::EquationMember;
+    use crate::prelude::*;
+
+    #[test]
+    fn test_chained_operations() {
+        let x = || Text("x".to_string());
+        let a: Expr = Expr::var("x") * 2.0 + 3.0;
+        assert_eq!(
+            a.clone().into_inner(),
+            Sum(vec![Multiply(vec![Value(2.0), x()]), Value(3.0)])
+        );
+        assert_eq!(a.node_count(), 5);
+
+        let b: Expr = Expr::constant(2.0) * 3.0 - 10.0;
+        assert_eq!(*b, Value(-4.0));
+
+        let c: Expr = (Expr::var("x") * 2.0 * 3.0) / Expr::var("y");
+        assert_eq!(
+            *c,
+            Divide(
+                Some(Box::new(Multiply(vec![Value(6.0), x()]))),
+                Some(Box::new(Text("y".to_string())))
+            )
+        );
+
+        let d: Expr = Expr::var("x") - Expr::var("x");
+        assert_eq!(*d, Value(0.0));
+
+        let e: Expr = -(Expr::var("x") + 1.0) * 0.0;
+        assert_eq!(*e, Value(0.0));
+        assert_eq!(e.value(), 0.0);
+    }
+
+    #[test]
+    fn test_integer_operands() {
+        let a: Expr = Expr::var("x") * 2 + 3;
+        assert_eq!(*a, *(Expr::var("x") * 2.0 + 3.0));
+        assert_eq!(a.equation_repr(), "2 * x + 3");
+
+        let b: Expr = Expr::var("x") - 4i64;
+        assert_eq!(*b, *(Expr::var("x") - 4.0));
+        assert_eq!(Expr::from(-7), Expr::constant(-7.0));
+        assert_eq!(Expr::from(1i64 << 40), Expr::constant(2f64.powi(40)));
+    }
+}
@@ -1,5 +1,4 @@
-#![allow(illegal_floating_point_literal_pattern)]
-
+pub mod expr;
 pub mod mappings;
 pub mod math;
 pub mod operations;
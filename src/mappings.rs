@@ -1,12 +1,17 @@
 use crate::prelude::*;
 
+/// A table of `(pattern, template)` pairs used to rewrite operations.
+///
+/// Patterns and templates reference the matched components by `Mapping` index.
+pub type MappingTable = Vec<(Operation, Operation)>;
+
 /// Create a mapping for operation expansion.
 ///
 /// In a perfect world this would be a constant but that will most likely require an
 /// intermediary data structure to be created. This is a temporary solution.
 /// We run into this issue with recursive types that need to be represented in the
 /// heap and thus cannot be represented as a constant as the heap does not exist at compile time.
-fn expansions() -> Vec<(Operation, Operation)> {
+fn expansions() -> MappingTable {
     vec![
         (
             Divide(
@@ -46,14 +51,14 @@ pub(crate) fn create_mapping_index(input: Operation) -> Vec<Operation> {
             }
         }
         Negate(Some(a)) => match *a {
-            Value(_) | Text(_) | Mapping(_) | Variable(_) => output.push(input),
+            Value(_) | Text(_) | Mapping(_) | Variable(_) | Rational(_, _) => output.push(input),
             _ => output.extend(create_mapping_index(*a)),
         },
         Divide(Some(n), Some(d)) | Equal(Some(n), Some(d)) => {
             output.extend(create_mapping_index(*n));
             output.extend(create_mapping_index(*d));
         }
-        Value(_) | Text(_) | Mapping(_) | Variable(_) => output.push(input),
+        Value(_) | Text(_) | Mapping(_) | Variable(_) | Rational(_, _) => output.push(input),
         _ => {}
     }
 
@@ -95,25 +100,11 @@ pub(crate) fn apply_mapping(input: &mut Operation, mappings: Vec<Operation>) ->
 ///
 /// This function maps the input `Operation` to another operation using the provided `mapping` function.
 /// It also checks for predefined expansions and applies them, resulting in a transformed operation.
-pub(crate) fn map<'a>(input: Operation, mapping: fn() -> Vec<(Operation, Operation)>) -> Operation {
-    let mut output: Operation = input.clone();
-    let mut negate: bool = false;
-    if let Negate(Some(_)) = output.clone() {
-        negate = true;
-    }
-    for (a, b) in mapping().iter() {
-        if output.compare_structure(a) {
-            output = b.clone();
-            break;
-        }
-    }
-    let mappings: Vec<Operation> = create_mapping_index(input);
-    let x = apply_mapping(&mut output, mappings);
-    if negate {
-        Negate(Some(Box::new(x)))
-    } else {
-        x
-    }
+pub(crate) fn map(input: Operation, mapping: fn() -> MappingTable) -> Operation {
+    let table: MappingTable = mapping();
+    (0..table.len())
+        .find_map(|rule| apply_rule_from(input.clone(), &table, rule).ok())
+        .unwrap_or(input)
 }
 
 /// Expand an operation by applying available mappings.
@@ -121,7 +112,6 @@ pub(crate) fn map<'a>(input: Operation, mapping: fn() -> Vec<(Operation, Operati
 /// This function attempts to expand the given `input` operation by applying predefined expansion
 /// mappings. If an expansion is successful, it returns the transformed operation wrapped in `Ok()`.
 /// If no expansion is possible, it returns the original operation wrapped in `Err()`.
-
 pub fn expand(input: Operation) -> Result<Operation, Operation> {
     let output: Operation = map(input.clone(), expansions);
     if output.compare_structure(&input) {
@@ -131,9 +121,132 @@ pub fn expand(input: Operation) -> Result<Operation, Operation> {
     }
 }
 
+/// Expand an operation unless the result would grow past `max_nodes`.
+///
+/// Behaves like `expand`, but if the expanded operation has more than `max_nodes` nodes
+/// (see `Operation::node_count`) the expansion is discarded and the original operation is
+/// returned wrapped in `Err()`.
+pub fn expand_bounded(input: Operation, max_nodes: usize) -> Result<Operation, Operation> {
+    match expand(input.clone()) {
+        Ok(output) if output.node_count() <= max_nodes => Ok(output),
+        Ok(_) => Err(input),
+        Err(output) => Err(output),
+    }
+}
+
+/// Apply a single expansion rule by its index in the expansion table.
+///
+/// Unlike `expand`, only the rule at `rule` is considered. If the input matches the rule's
+/// pattern the rewritten operation is returned wrapped in `Ok()`, otherwise the input is
+/// returned unchanged wrapped in `Err()`.
+pub fn apply_rule(input: Operation, rule: usize) -> Result<Operation, Operation> {
+    apply_rule_from(input, &expansions(), rule)
+}
+
+/// Apply a single rule by its index in a user provided `MappingTable`.
+///
+/// See `apply_rule` for the meaning of the returned `Result`.
+pub fn apply_rule_from(
+    input: Operation,
+    table: &MappingTable,
+    rule: usize,
+) -> Result<Operation, Operation> {
+    match table.get(rule) {
+        Some((pattern, template)) if input.compare_structure(pattern) => {
+            let mut output: Operation = template.clone();
+            let x = apply_mapping(&mut output, create_mapping_index(input.clone()));
+            if let Negate(Some(_)) = input {
+                Ok(Negate(Some(Box::new(x))))
+            } else {
+                Ok(x)
+            }
+        }
+        _ => Err(input),
+    }
+}
+
+/// A user defined rewrite, see `Operation::rewrite`.
+///
+/// Unlike the `MappingTable` patterns, which map leaves by position, each `Mapping(i)` in
+/// `pattern` captures a whole subtree. Every occurrence of `Mapping(i)` must capture the
+/// same subtree, and is replaced by it in `template`. Other nodes of the pattern must match
+/// exactly.
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    pub pattern: Operation,
+    pub template: Operation,
+}
+
+impl RewriteRule {
+    pub fn new(pattern: Operation, template: Operation) -> RewriteRule {
+        RewriteRule { pattern, template }
+    }
+
+    /// Rewrites `input` if it matches the pattern, returning `None` otherwise.
+    pub fn apply(&self, input: &Operation) -> Option<Operation> {
+        let mut captures: Vec<Option<Operation>> = Vec::new();
+        if !capture(&self.pattern, input, &mut captures) {
+            return None;
+        }
+        let mut output: Operation = self.template.clone();
+        substitute(&mut output, &captures);
+        Some(output)
+    }
+}
+
+/// Matches `input` against `pattern`, recording the subtree captured by each `Mapping`.
+pub(crate) fn capture(
+    pattern: &Operation,
+    input: &Operation,
+    captures: &mut Vec<Option<Operation>>,
+) -> bool {
+    if let Mapping(index) = pattern {
+        if captures.len() <= *index {
+            captures.resize(*index + 1, None);
+        }
+        return match &captures[*index] {
+            Some(existing) => existing == input,
+            None => {
+                captures[*index] = Some(input.clone());
+                true
+            }
+        };
+    }
+    let (pattern_children, input_children) = (pattern.children(), input.children());
+    if pattern.print_operation_type() != input.print_operation_type()
+        || pattern_children.len() != input_children.len()
+    {
+        return false;
+    }
+    if pattern_children.is_empty() {
+        return pattern == input;
+    }
+    pattern_children
+        .into_iter()
+        .zip(input_children)
+        .all(|(x, y)| capture(x, y, captures))
+}
+
+/// Replaces every captured `Mapping` in `template`.
+fn substitute(template: &mut Operation, captures: &[Option<Operation>]) {
+    if let Mapping(index) = template {
+        if let Some(Some(a)) = captures.get(*index) {
+            *template = a.clone();
+        }
+        return;
+    }
+    template
+        .children_mut()
+        .into_iter()
+        .for_each(|x| substitute(x, captures));
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::mappings::{create_mapping_index, expand};
+    use crate::mappings::{
+        apply_rule, apply_rule_from, create_mapping_index, expand, expand_bounded, MappingTable,
+        RewriteRule,
+    };
     use crate::prelude::*;
 
     #[test]
@@ -254,6 +367,20 @@ mod tests {
         assert_eq!(expand(a), Ok(b));
     }
 
+    #[test]
+    fn test_expand_bounded() {
+        let a: Operation = Divide(
+            Some(Box::new(Sum(vec![
+                Text("x".to_string()),
+                Text("y".to_string()),
+            ]))),
+            Some(Box::new(Text("z".to_string()))),
+        );
+        assert_eq!(expand_bounded(a.clone(), 6), Err(a.clone()));
+        assert_eq!(expand_bounded(a.clone(), 7), expand(a));
+        assert!(expand_bounded(Text("x".to_string()), 100).is_err());
+    }
+
     #[test]
     fn test_create_mapping_index() {
         let a: Operation = Divide(
@@ -363,4 +490,75 @@ mod tests {
         );
         assert!(a.compare_structure(&b));
     }
+
+    #[test]
+    fn test_apply_rule() {
+        let x = || Box::new(Text("x".to_string()));
+        let y = || Box::new(Text("y".to_string()));
+        let z = || Box::new(Text("z".to_string()));
+
+        let a: Operation = Divide(Some(Box::new(Sum(vec![*x(), *y()]))), Some(z()));
+        let b: Operation = Sum(vec![
+            Divide(Some(x()), Some(z())),
+            Divide(Some(y()), Some(z())),
+        ]);
+        assert_eq!(apply_rule(a.clone(), 0), Ok(b));
+
+        // Rule 1 expects three terms in the numerator
+        assert_eq!(apply_rule(a.clone(), 1), Err(a.clone()));
+
+        let a: Operation = Divide(Some(Box::new(Sum(vec![*x(), *y(), Value(2.0)]))), Some(z()));
+        let b: Operation = Sum(vec![
+            Divide(Some(x()), Some(z())),
+            Divide(Some(y()), Some(z())),
+            Divide(Some(Box::new(Value(2.0))), Some(z())),
+        ]);
+        assert_eq!(apply_rule(a.clone(), 1), Ok(b));
+        assert_eq!(apply_rule(a.clone(), 0), Err(a.clone()));
+
+        // Out of range rules never match
+        assert_eq!(apply_rule(a.clone(), 99), Err(a));
+    }
+
+    #[test]
+    fn test_apply_rule_from_table() {
+        let table: MappingTable = vec![(
+            Multiply(vec![Mapping(0), Sum(vec![Mapping(1), Mapping(2)])]),
+            Sum(vec![
+                Multiply(vec![Mapping(0), Mapping(1)]),
+                Multiply(vec![Mapping(0), Mapping(2)]),
+            ]),
+        )];
+        let a: Operation = Multiply(vec![
+            Text("a".to_string()),
+            Sum(vec![Text("x".to_string()), Text("y".to_string())]),
+        ]);
+        let b: Operation = Sum(vec![
+            Multiply(vec![Text("a".to_string()), Text("x".to_string())]),
+            Multiply(vec![Text("a".to_string()), Text("y".to_string())]),
+        ]);
+        assert_eq!(apply_rule_from(a, &table, 0), Ok(b));
+    }
+
+    #[test]
+    fn test_rewrite_rule() {
+        let t = |x: &str| Text(x.to_string());
+        // x + x = 2 * x
+        let rule = RewriteRule::new(
+            Sum(vec![Mapping(0), Mapping(0)]),
+            Multiply(vec![Value(2.0), Mapping(0)]),
+        );
+        let double: Operation = Power(Some(Box::new(t("y"))), Some(Box::new(Value(2.0))));
+        assert_eq!(
+            rule.apply(&Sum(vec![double.clone(), double.clone()])),
+            Some(Multiply(vec![Value(2.0), double]))
+        );
+        assert_eq!(rule.apply(&Sum(vec![t("x"), t("y")])), None);
+        assert_eq!(rule.apply(&Sum(vec![t("x"), t("x"), t("x")])), None);
+        // Values are compared exactly, not as rendered
+        assert_eq!(rule.apply(&Sum(vec![Value(1.0001), Value(1.0004)])), None);
+        let one = RewriteRule::new(Value(1.0), t("one"));
+        assert_eq!(one.apply(&Value(1.0004)), None);
+        assert_eq!(one.apply(&Value(1.0)), Some(t("one")));
+    }
 }
@@ -1,8 +1,10 @@
 use crate::operations::Operation;
+use crate::operations::Operation::{Divide, Equal, Multiply, Negate, Sum, Value};
 use nalgebra::{DMatrix, DVector};
 use ndarray::{Array2, ArrayBase, Ix2, OwnedRepr};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
-use std::rc::Rc;
+use std::hash::{Hash, Hasher};
 
 pub trait EquationMember {
     /// Returns a string representation of the equation
@@ -22,7 +24,7 @@ pub trait EquationMember {
     /// Returns true if the value of the equation is zero
     /// This is important for the required array solver traits
     fn is_zero(&self) -> bool {
-        self.value().is_zero()
+        self.value() == 0.0
     }
 
     /// Returns a latex representation of the equation for the front end
@@ -36,6 +38,23 @@ pub trait EquationMember {
     }
 }
 
+/// The pointer used to share members between operations.
+///
+/// This is `Rc` by default and `Arc` with the `sync` feature enabled, which makes
+/// `Operation` `Send` and `Sync` so trees can be processed on other threads.
+#[cfg(not(feature = "sync"))]
+pub type Shared<T> = std::rc::Rc<T>;
+#[cfg(feature = "sync")]
+pub type Shared<T> = std::sync::Arc<T>;
+
+/// A shared `EquationMember` as stored by `Operation::Variable` and `Operation::Display`.
+///
+/// With the `sync` feature enabled members must also be `Send` and `Sync`.
+#[cfg(not(feature = "sync"))]
+pub type SharedMember = Shared<dyn EquationMember>;
+#[cfg(feature = "sync")]
+pub type SharedMember = Shared<dyn EquationMember + Send + Sync>;
+
 #[derive(Debug, Clone)]
 pub struct EquationRepr {
     string: String,
@@ -43,9 +62,42 @@ pub struct EquationRepr {
     value: f64,
 }
 
-pub(crate) trait EquationSolver {
-    fn solve(&self) -> Result<f64, String>;
-    fn simplify(&self) -> Result<Equation, String>;
+/// Why an `EquationSolver` could not solve an equation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SolveError {
+    /// The unknown appears other than to the first power.
+    Nonlinear,
+    /// The equation has more than one unknown, listed by name.
+    MultipleUnknowns(Vec<String>),
+    /// The equation reduces to a false statement such as `0 = 1`.
+    NoSolution,
+    /// The equation holds for any value of the unknown.
+    InfiniteSolutions,
+    /// The equation divides by a constant zero.
+    DivisionByZero,
+}
+
+impl std::fmt::Display for SolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SolveError::Nonlinear => write!(f, "Equation is not linear in the unknown"),
+            SolveError::MultipleUnknowns(names) => {
+                write!(f, "Expected one unknown, found [{}]", names.join(", "))
+            }
+            SolveError::NoSolution => write!(f, "Equation has no solution"),
+            SolveError::InfiniteSolutions => write!(f, "Equation has infinitely many solutions"),
+            SolveError::DivisionByZero => write!(f, "Division by zero"),
+        }
+    }
+}
+
+impl std::error::Error for SolveError {}
+
+pub trait EquationSolver {
+    /// Solves for the only unknown of the equation.
+    fn solve(&self) -> Result<f64, SolveError>;
+    /// Simplifies both sides of the equation.
+    fn simplify(&self) -> Result<Equation, SolveError>;
 }
 
 #[derive(Debug, Clone)]
@@ -76,6 +128,209 @@ impl EquationMember for Equation {
     }
 }
 
+impl PartialEq for Equation {
+    fn eq(&self, other: &Self) -> bool {
+        self.left == other.left && self.right == other.right
+    }
+}
+
+impl Eq for Equation {}
+
+impl Hash for Equation {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.left.hash(state);
+        self.right.hash(state);
+    }
+}
+
+impl Equation {
+    pub fn new(left: Operation, right: Operation) -> Equation {
+        Equation { left, right }
+    }
+
+    /// Returns `left - right` with the symbols named in `bindings` set to their values.
+    ///
+    /// Returns NaN if either side references an unbound symbol.
+    pub fn residual(&self, bindings: &HashMap<String, f64>) -> f64 {
+        match (self.left.evaluate(bindings), self.right.evaluate(bindings)) {
+            (Ok(left), Ok(right)) => left - right,
+            _ => f64::NAN,
+        }
+    }
+
+    /// Returns true if every unknown of the equation appears only to the first power.
+    ///
+    /// Products of two unknowns, unknowns in a divisor or exponent and powers other than one
+    /// of an unknown make the equation nonlinear. Symbols with a value are constants, see
+    /// `unknowns`.
+    pub fn is_linear(&self) -> bool {
+        Sum(vec![
+            self.left.clone(),
+            Negate(Some(Box::new(self.right.clone()))),
+        ])
+        .polynomial_degree(&self.unknowns())
+        .is_some_and(|x| x <= 1)
+    }
+
+    /// Returns the names of the symbols without a finite value, sorted.
+    pub fn unknowns(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .left
+            .get_variables()
+            .iter()
+            .chain(self.right.get_variables().iter())
+            .filter(|x| !x.get_child().is_some_and(|a| a.value().is_finite()))
+            .map(|x| x.equation_repr())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Solves a linear equation for the symbol `var`.
+    ///
+    /// Every other symbol must be a `Variable` with a finite value, otherwise the unknowns
+    /// are reported with `SolveError::MultipleUnknowns`.
+    pub fn solve_for(&self, var: &str) -> Result<f64, SolveError> {
+        let mut unknowns: Vec<String> = self.unknowns();
+        if unknowns.iter().any(|x| x != var) {
+            if !unknowns.iter().any(|x| x == var) {
+                unknowns.push(var.to_string());
+                unknowns.sort();
+            }
+            return Err(SolveError::MultipleUnknowns(unknowns));
+        }
+        self.solve_linear(&[var.to_string()])
+    }
+
+    /// Solves the equation for the unknowns in `vars`, at most one, once the other symbols
+    /// are known to have a value.
+    ///
+    /// Without an unknown the equation either always holds or never does. A solution that
+    /// is not finite, e.g. from overflowing coefficients, is reported as no solution.
+    fn solve_linear(&self, vars: &[String]) -> Result<f64, SolveError> {
+        if divides_by_zero(&self.left) || divides_by_zero(&self.right) {
+            return Err(SolveError::DivisionByZero);
+        }
+        let difference: Operation = Sum(vec![
+            self.left.clone(),
+            Negate(Some(Box::new(self.right.clone()))),
+        ]);
+        let (coefficients, constant) = difference
+            .linear_coefficients(vars)
+            .ok_or(SolveError::Nonlinear)?;
+        match (coefficients.first().copied().unwrap_or(0.0), constant) {
+            (0.0, 0.0) => Err(SolveError::InfiniteSolutions),
+            (0.0, _) => Err(SolveError::NoSolution),
+            (a, b) if (-b / a).is_finite() => Ok(-b / a),
+            _ => Err(SolveError::NoSolution),
+        }
+    }
+
+    /// Moves every term to the left side, returning `left - right = 0` with the left side
+    /// simplified, see `Operation::normalize_equal`.
+    pub fn to_zero_form(&self) -> Equation {
+        let equal: Operation = Equal(
+            Some(Box::new(self.left.clone())),
+            Some(Box::new(self.right.clone())),
+        );
+        match equal.normalize_equal() {
+            Some(Equal(Some(left), Some(right))) => Equation::new(*left, *right),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Merges nested sums and products on both sides, see `Operation::flatten_associative`.
+    pub fn flatten_associative(&self) -> Equation {
+        Equation::new(
+            self.left.flatten_associative(),
+            self.right.flatten_associative(),
+        )
+    }
+
+    /// Reports the locations where two equations differ.
+    ///
+    /// Both sides are walked in parallel and each mismatching node is described by its
+    /// path, e.g. `left.1: Value(2) vs Value(3)`. Child indices follow the order of the
+    /// operands, so `0` and `1` select the numerator and divisor of a `Divide`. The terms of
+    /// a `Sum` or `Multiply` are unordered, so terms with an equal counterpart are skipped
+    /// and the remaining ones are paired in order.
+    pub fn diff(&self, other: &Equation) -> Vec<String> {
+        let mut output: Vec<String> = Vec::new();
+        diff_operations("left", &self.left, &other.left, &mut output);
+        diff_operations("right", &self.right, &other.right, &mut output);
+        output
+    }
+}
+
+impl EquationSolver for Equation {
+    fn solve(&self) -> Result<f64, SolveError> {
+        match &self.unknowns()[..] {
+            [var] => self.solve_for(var),
+            [] => self.solve_linear(&[]),
+            unknowns => Err(SolveError::MultipleUnknowns(unknowns.to_vec())),
+        }
+    }
+
+    fn simplify(&self) -> Result<Equation, SolveError> {
+        Ok(Equation::new(
+            self.left.simplify_fully(),
+            self.right.simplify_fully(),
+        ))
+    }
+}
+
+/// Checks whether the operation divides by something that evaluates to zero without any
+/// unknowns.
+fn divides_by_zero(op: &Operation) -> bool {
+    if let Divide(_, Some(b)) = op {
+        if b.evaluate(&HashMap::new()) == Ok(0.0) {
+            return true;
+        }
+    }
+    op.children().into_iter().any(divides_by_zero)
+}
+
+fn diff_operations(path: &str, a: &Operation, b: &Operation, output: &mut Vec<String>) {
+    if a == b {
+        return;
+    }
+    let (a_children, b_children) = (a.children(), b.children());
+    let same_shape: bool = a.print_operation_type() == b.print_operation_type()
+        && a_children.len() == b_children.len();
+    if !same_shape || a_children.is_empty() {
+        // Values are written in full, `equation_repr` rounds them
+        let describe = |x: &Operation| match x {
+            Value(a) => a.to_string(),
+            x => x.equation_repr(),
+        };
+        output.push(format!(
+            "{}: {}({}) vs {}({})",
+            path,
+            a.print_operation_type(),
+            describe(a),
+            b.print_operation_type(),
+            describe(b)
+        ));
+        return;
+    }
+    let mut pairs: Vec<(usize, &Operation)> = a_children.into_iter().enumerate().collect();
+    let mut others: Vec<&Operation> = b_children;
+    if let Sum(_) | Multiply(_) = a {
+        // Terms are unordered, so only those without an equal counterpart are compared
+        pairs.retain(|(_, x)| match others.iter().position(|y| x == y) {
+            Some(j) => {
+                others.remove(j);
+                false
+            }
+            None => true,
+        });
+    }
+    for ((i, x), y) in pairs.into_iter().zip(others) {
+        diff_operations(&format!("{}.{}", path, i), x, y, output);
+    }
+}
+
 impl EquationMember for EquationRepr {
     fn equation_repr(&self) -> String {
         self.string.clone()
@@ -109,14 +364,45 @@ impl EquationRepr {
     }
 }
 
+/// Magnitudes below this are written in scientific notation by `format_number`, as they
+/// would otherwise round to zero at three decimals.
+pub const SCIENTIFIC_BELOW: f64 = 1e-3;
+/// Magnitudes at or above this are written in scientific notation by `format_number`.
+pub const SCIENTIFIC_FROM: f64 = 1e6;
+
+/// Formats a number rounded to three decimals, switching to scientific notation with a
+/// three decimal mantissa for nonzero magnitudes below `below` or at or above `from`.
+pub fn format_number(value: f64, below: f64, from: f64) -> String {
+    let magnitude: f64 = value.abs();
+    if !value.is_finite() || magnitude == 0.0 || (below..from).contains(&magnitude) {
+        let rounded = (value * 1000.0).round() / 1000.0;
+        return rounded.to_string();
+    }
+    let mut exponent: i32 = magnitude.log10().floor() as i32;
+    let mut mantissa: f64 = (value / 10f64.powi(exponent) * 1000.0).round() / 1000.0;
+    if mantissa.abs() >= 10.0 {
+        mantissa /= 10.0;
+        exponent += 1;
+    }
+    format!("{}e{}", mantissa, exponent)
+}
+
 impl EquationMember for f64 {
     fn equation_repr(&self) -> String {
-        let rounded = (self * 1000.0).round() / 1000.0;
-        rounded.to_string()
+        format_number(*self, SCIENTIFIC_BELOW, SCIENTIFIC_FROM)
     }
     fn value(&self) -> f64 {
         *self
     }
+
+    /// Writes scientific notation as a power of ten, e.g. `1e-6` as `1 \times 10^{-6}`.
+    fn latex_string(&self) -> String {
+        let repr: String = self.equation_repr();
+        match repr.split_once('e') {
+            Some((mantissa, exponent)) => format!("{} \\times 10^{{{}}}", mantissa, exponent),
+            None => repr,
+        }
+    }
 }
 
 impl EquationMember for usize {
@@ -129,6 +415,44 @@ impl EquationMember for usize {
     }
 }
 
+impl EquationMember for i32 {
+    fn equation_repr(&self) -> String {
+        self.to_string()
+    }
+
+    fn value(&self) -> f64 {
+        *self as f64
+    }
+}
+
+impl EquationMember for i64 {
+    fn equation_repr(&self) -> String {
+        self.to_string()
+    }
+
+    fn value(&self) -> f64 {
+        *self as f64
+    }
+}
+
+impl EquationMember for bool {
+    fn equation_repr(&self) -> String {
+        self.to_string()
+    }
+
+    fn value(&self) -> f64 {
+        if *self {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        !self
+    }
+}
+
 impl EquationMember for ArrayBase<OwnedRepr<Operation>, Ix2> {
     fn equation_repr(&self) -> String {
         matrix_to_latex(self.clone())
@@ -166,6 +490,56 @@ impl<T: EquationMember> EquationMember for DMatrix<T> {
     }
 }
 
+/// Builds the Jacobian of a system of equations with respect to `vars`.
+///
+/// Entry `(i, j)` is the simplified partial derivative of the residual `left - right` of
+/// equation `i` with respect to `vars[j]`. Entries that cannot be differentiated
+/// symbolically are `Value(f64::NAN)`.
+pub fn jacobian(system: &[Equation], vars: &[String]) -> Array2<Operation> {
+    Array2::from_shape_fn((system.len(), vars.len()), |(i, j)| {
+        let residual: Operation = Sum(vec![
+            system[i].left.clone(),
+            Negate(Some(Box::new(system[i].right.clone()))),
+        ]);
+        match residual.derivative(&vars[j]) {
+            Some(derivative) => derivative.simplify_fully(),
+            None => Value(f64::NAN),
+        }
+    })
+}
+
+/// Generates fresh variable names `{v_1}`, `{v_2}`, ... for symbols introduced while
+/// manipulating equations, skipping any name that is already taken.
+#[derive(Debug, Clone, Default)]
+pub struct VariableGenerator {
+    counter: usize,
+    taken: HashSet<String>,
+}
+
+impl VariableGenerator {
+    pub fn new(existing: &[String]) -> VariableGenerator {
+        VariableGenerator {
+            counter: 0,
+            taken: existing.iter().cloned().collect(),
+        }
+    }
+}
+
+impl Iterator for VariableGenerator {
+    type Item = String;
+
+    /// Returns the next unused name. The generator never runs out.
+    fn next(&mut self) -> Option<String> {
+        loop {
+            self.counter += 1;
+            let name: String = format!("{{v_{}}}", self.counter);
+            if self.taken.insert(name.clone()) {
+                return Some(name);
+            }
+        }
+    }
+}
+
 pub fn matrix_to_latex(matrix: Array2<Operation>) -> String {
     let mut latex_a_matrix = String::new();
     latex_a_matrix.push_str("\\begin{bmatrix}");
@@ -182,11 +556,52 @@ pub fn matrix_to_latex(matrix: Array2<Operation>) -> String {
     latex_a_matrix
 }
 
-impl<T> From<Rc<T>> for EquationRepr
+/// Converts an ndarray matrix of operations to a nalgebra matrix with the same entries.
+pub fn to_dmatrix(a: &Array2<Operation>) -> DMatrix<Operation> {
+    DMatrix::from_fn(a.nrows(), a.ncols(), |i, j| a[[i, j]].clone())
+}
+
+/// Converts a nalgebra matrix of operations to an ndarray matrix with the same entries.
+pub fn to_array2(a: &DMatrix<Operation>) -> Array2<Operation> {
+    Array2::from_shape_fn((a.nrows(), a.ncols()), |(i, j)| a[(i, j)].clone())
+}
+
+/// Concatenates two systems of equations, dropping every equation that repeats an earlier
+/// one once both are in `Equation::to_zero_form`.
+///
+/// The first occurrence is kept. Equations are compared exactly with `==`, so values that
+/// only differ past the rendered precision are kept apart. Equations that only differ by a
+/// factor, such as `x = y` and `y = x`, are not considered duplicates.
+pub fn merge_systems(a: &[Equation], b: &[Equation]) -> Vec<Equation> {
+    let mut seen: HashSet<Equation> = HashSet::new();
+    a.iter()
+        .chain(b)
+        .filter(|x| seen.insert(x.to_zero_form()))
+        .cloned()
+        .collect()
+}
+
+/// Renders a system of equations as a LaTeX `cases` environment, one equation per line.
+pub fn system_to_latex(system: &[Equation]) -> String {
+    let mut latex_system = String::new();
+    latex_system.push_str("\\begin{cases}");
+    for (i, equation) in system.iter().enumerate() {
+        latex_system.push_str(&equation.left.latex_string());
+        latex_system.push_str(" = ");
+        latex_system.push_str(&equation.right.latex_string());
+        if i != system.len() - 1 {
+            latex_system.push_str("\\\\"); // Don't end the last line
+        }
+    }
+    latex_system.push_str("\\end{cases}");
+    latex_system
+}
+
+impl<T> From<Shared<T>> for EquationRepr
 where
     T: EquationMember,
 {
-    fn from(rc: Rc<T>) -> Self {
+    fn from(rc: Shared<T>) -> Self {
         EquationRepr::new_with_latex(rc.equation_repr(), rc.latex_string(), rc.value())
     }
 }
@@ -202,7 +617,395 @@ impl EquationMember for (String, f64) {
 
 #[cfg(test)]
 mod tests {
+    use crate::math::{
+        format_number, jacobian, merge_systems, system_to_latex, to_array2, to_dmatrix, Equation,
+        EquationMember, EquationSolver, SolveError, VariableGenerator,
+    };
+    use crate::prelude::*;
+    use nalgebra::DMatrix;
+    use ndarray::Array2;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::{HashMap, HashSet};
+    use std::hash::{Hash, Hasher};
+
+    fn hash_of(equation: &Equation) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        equation.hash(&mut hasher);
+        hasher.finish()
+    }
 
     #[test]
     fn test() {}
+
+    #[test]
+    fn test_default_is_zero() {
+        assert!(0.0.is_zero());
+        assert!(!1.5.is_zero());
+        assert!(0usize.is_zero());
+    }
+
+    #[test]
+    fn test_integer_members() {
+        assert_eq!(42i32.equation_repr(), "42");
+        assert_eq!((-7i32).equation_repr(), "-7");
+        assert_eq!((-7i32).value(), -7.0);
+        assert_eq!((-9_000_000_000i64).equation_repr(), "-9000000000");
+        assert_eq!((-9_000_000_000i64).value(), -9e9);
+        assert!(0i64.is_zero());
+        assert_eq!((-3i64).latex_string(), "-3");
+    }
+
+    #[test]
+    fn test_bool_members() {
+        assert_eq!(true.equation_repr(), "true");
+        assert_eq!(false.latex_string(), "false");
+        assert_eq!(true.value(), 1.0);
+        assert!(false.is_zero());
+        assert!(!true.is_zero());
+
+        let left: Operation = Multiply(vec![Value(2.0), Value(3.0)]);
+        let right: Operation = Value(5.0);
+        let predicate: SharedMember = Shared::new(left.value() <= right.value());
+        assert_eq!(predicate.equation_repr(), "false");
+        assert!(predicate.is_zero());
+        let member: Operation = Variable(predicate);
+        assert_eq!(member.value(), 0.0);
+    }
+
+    #[test]
+    fn test_equation_flatten_associative() {
+        let t = |x: &str| Text(x.to_string());
+        let a: Equation = Equation::new(
+            Sum(vec![Sum(vec![Sum(vec![t("a"), t("b")]), t("c")]), t("d")]),
+            Multiply(vec![Multiply(vec![t("e"), t("f")])]),
+        );
+        assert_eq!(
+            a.flatten_associative(),
+            Equation::new(
+                Sum(vec![t("a"), t("b"), t("c"), t("d")]),
+                Multiply(vec![t("e"), t("f")])
+            )
+        );
+    }
+
+    #[test]
+    fn test_variable_generator() {
+        let mut generator = VariableGenerator::new(&["{v_2}".to_string(), "x".to_string()]);
+        assert_eq!(generator.next().as_deref(), Some("{v_1}"));
+        assert_eq!(generator.next().as_deref(), Some("{v_3}"));
+        let names: HashSet<String> = generator.take(10).collect();
+        assert_eq!(names.len(), 10);
+        assert!(!names.contains("{v_2}"));
+        assert!(!names.contains("{v_1}"));
+    }
+
+    #[test]
+    fn test_system_to_latex() {
+        let system: Vec<Equation> = vec![
+            Equation::new(
+                Sum(vec![Text("x".to_string()), Text("y".to_string())]),
+                Value(3.0),
+            ),
+            Equation::new(
+                Multiply(vec![Value(2.0), Text("x".to_string())]),
+                Text("y".to_string()),
+            ),
+        ];
+        assert_eq!(
+            system_to_latex(&system),
+            "\\begin{cases}{$x$ + $y$} = 3\\\\2$x$ = $y$\\end{cases}"
+        );
+    }
+
+    #[test]
+    fn test_matrix_conversion() {
+        let a: Array2<Operation> =
+            Array2::from_shape_fn((2, 3), |(i, j)| Value((i * 3 + j) as f64));
+        let b: DMatrix<Operation> = to_dmatrix(&a);
+        assert_eq!(b.shape(), (2, 3));
+        assert_eq!(b[(0, 2)], Value(2.0));
+        assert_eq!(b[(1, 0)], Value(3.0));
+        assert_eq!(to_array2(&b), a);
+    }
+
+    #[test]
+    fn test_number_formatting() {
+        assert_eq!(1e-6.equation_repr(), "1e-6");
+        assert_eq!((-2.5e-5).equation_repr(), "-2.5e-5");
+        assert_eq!(0.0004.equation_repr(), "4e-4");
+        assert_eq!(0.001.equation_repr(), "0.001");
+        assert_eq!(0.0.equation_repr(), "0");
+        assert_eq!(2.0.equation_repr(), "2");
+        assert_eq!(12.34567.equation_repr(), "12.346");
+        assert_eq!(999999.0.equation_repr(), "999999");
+        assert_eq!(1234567.891.equation_repr(), "1.235e6");
+        assert_eq!(9999999.9.equation_repr(), "1e7");
+        assert_eq!(f64::INFINITY.equation_repr(), "inf");
+        assert_eq!(format_number(0.5, 1.0, 10.0), "5e-1");
+        assert_eq!(format_number(1e-6, 0.0, f64::INFINITY), "0");
+
+        assert_eq!(1e-6.latex_string(), "1 \\times 10^{-6}");
+        assert_eq!(1234567.891.latex_string(), "1.235 \\times 10^{6}");
+        assert_eq!(12.34567.latex_string(), "12.346");
+        assert_eq!(f64::INFINITY.latex_string(), "inf");
+        assert_eq!(Value(-2.5e-5).latex_string(), "-2.5 \\times 10^{-5}");
+    }
+
+    #[test]
+    fn test_equation_diff() {
+        let a: Equation = Equation::new(
+            Sum(vec![
+                Multiply(vec![Value(2.0), Text("x".to_string())]),
+                Text("y".to_string()),
+            ]),
+            Value(5.0),
+        );
+        let b: Equation = Equation::new(
+            Sum(vec![
+                Multiply(vec![Value(3.0), Text("x".to_string())]),
+                Text("y".to_string()),
+            ]),
+            Value(5.0),
+        );
+        assert_eq!(
+            a.diff(&b),
+            vec!["left.0.0: Value(2) vs Value(3)".to_string()]
+        );
+        assert!(a.diff(&a.clone()).is_empty());
+
+        // Terms are unordered and values are compared exactly
+        let reordered: Equation = Equation::new(
+            Sum(vec![
+                Text("y".to_string()),
+                Multiply(vec![Text("x".to_string()), Value(2.0)]),
+            ]),
+            Value(5.0),
+        );
+        assert!(a.diff(&reordered).is_empty());
+        assert_eq!(
+            reordered.diff(&b),
+            vec!["left.1.1: Value(2) vs Value(3)".to_string()]
+        );
+        let x: Equation = Equation::new(Text("x".to_string()), Value(1.0001));
+        let y: Equation = Equation::new(Text("x".to_string()), Value(1.0002));
+        assert_eq!(
+            x.diff(&y),
+            vec!["right: Value(1.0001) vs Value(1.0002)".to_string()]
+        );
+
+        let c: Equation = Equation::new(Text("y".to_string()), Value(5.0));
+        assert_eq!(
+            a.diff(&c),
+            vec!["left: Sum(2 * x + y) vs Text(y)".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_equation_hash() {
+        let a: Equation = Equation::new(Sum(vec![Text("x".to_string()), Value(2.0)]), Value(0.0));
+        // Same terms in a different order
+        let b: Equation = Equation::new(Sum(vec![Value(2.0), Text("x".to_string())]), Value(-0.0));
+        let c: Equation = Equation::new(Sum(vec![Text("x".to_string()), Value(3.0)]), Value(0.0));
+        assert_eq!(a, b);
+        assert_eq!(hash_of(&a), hash_of(&b));
+        assert_ne!(a, c);
+        assert_ne!(hash_of(&a), hash_of(&c));
+
+        let mut cache: HashMap<Equation, usize> = HashMap::new();
+        cache.insert(a, 1);
+        assert_eq!(cache.get(&b), Some(&1));
+        assert_eq!(cache.get(&c), None);
+
+        let x: Operation = Operation::variable("x");
+        let d: Equation = Equation::new(Sum(vec![x.clone(), Value(1.0)]), x.clone());
+        assert_eq!(d, d.clone());
+        let set: HashSet<Equation> = HashSet::from([d.clone()]);
+        assert!(set.contains(&d));
+        let equal: Operation = Equal(Some(Box::new(x)), Some(Box::new(Value(1.0))));
+        assert_eq!(equal, equal.clone());
+    }
+
+    #[test]
+    fn test_equation_residual() {
+        // 2x + 3 = y
+        let equation: Equation = Equation::new(
+            Sum(vec![
+                Multiply(vec![Value(2.0), Text("x".to_string())]),
+                Value(3.0),
+            ]),
+            Text("y".to_string()),
+        );
+        let bindings = |x: f64, y: f64| HashMap::from([("x".to_string(), x), ("y".to_string(), y)]);
+        assert_eq!(equation.residual(&bindings(1.0, 5.0)), 0.0);
+        assert_eq!(equation.residual(&bindings(2.0, 5.0)), 2.0);
+        assert_eq!(equation.residual(&bindings(0.0, 10.0)), -7.0);
+
+        let unbound: HashMap<String, f64> = HashMap::from([("x".to_string(), 1.0)]);
+        assert!(equation.residual(&unbound).is_nan());
+        assert!(Text("y".to_string()).evaluate(&unbound).is_err());
+    }
+
+    #[test]
+    fn test_jacobian() {
+        // x^2 + y = 3, x * y = 2
+        let system: Vec<Equation> = vec![
+            Equation::new(
+                Sum(vec![
+                    Power(
+                        Some(Box::new(Text("x".to_string()))),
+                        Some(Box::new(Value(2.0))),
+                    ),
+                    Text("y".to_string()),
+                ]),
+                Value(3.0),
+            ),
+            Equation::new(
+                Multiply(vec![Text("x".to_string()), Text("y".to_string())]),
+                Value(2.0),
+            ),
+        ];
+        let vars: Vec<String> = vec!["x".to_string(), "y".to_string()];
+        let matrix = jacobian(&system, &vars);
+        assert_eq!(matrix.dim(), (2, 2));
+        assert_eq!(matrix[[0, 1]], Value(1.0));
+
+        let bindings: HashMap<String, f64> =
+            HashMap::from([("x".to_string(), 2.0), ("y".to_string(), 5.0)]);
+        let values: Vec<f64> = matrix
+            .iter()
+            .map(|x| x.evaluate(&bindings).unwrap())
+            .collect();
+        assert_eq!(values, vec![4.0, 1.0, 5.0, 2.0]);
+    }
+
+    #[test]
+    fn test_equation_is_linear() {
+        let x = || Text("x".to_string());
+        let y = || Text("y".to_string());
+        let linear: Equation = Equation::new(
+            Sum(vec![
+                Multiply(vec![Value(2.0), x()]),
+                Multiply(vec![Value(3.0), y()]),
+            ]),
+            Value(5.0),
+        );
+        assert!(linear.is_linear());
+
+        let product: Equation = Equation::new(Multiply(vec![x(), y()]), Value(1.0));
+        assert!(!product.is_linear());
+
+        let square: Equation = Equation::new(
+            Power(Some(Box::new(x())), Some(Box::new(Value(2.0)))),
+            Value(4.0),
+        );
+        assert!(!square.is_linear());
+
+        let reciprocal: Equation =
+            Equation::new(Divide(Some(Box::new(Value(1.0))), Some(Box::new(x()))), y());
+        assert!(!reciprocal.is_linear());
+
+        // Variables with a value are constants
+        let k = || Operation::variable_with_value("k", 2.0);
+        assert!(Equation::new(Multiply(vec![k(), x()]), Value(1.0)).is_linear());
+        assert!(Equation::new(Divide(Some(Box::new(x())), Some(Box::new(k()))), y()).is_linear());
+    }
+
+    #[test]
+    fn test_merge_systems() {
+        let t = |x: &str| Text(x.to_string());
+        let a: Vec<Equation> = vec![
+            Equation::new(Sum(vec![t("x"), t("y")]), Value(3.0)),
+            Equation::new(Multiply(vec![Value(2.0), t("x")]), t("y")),
+        ];
+        let b: Vec<Equation> = vec![
+            // x + y = 3 written as x + y - 3 = 0
+            Equation::new(Sum(vec![t("x"), t("y"), Value(-3.0)]), Value(0.0)),
+            Equation::new(Multiply(vec![t("x"), Value(2.0)]), t("y")),
+            Equation::new(t("z"), Value(1.0)),
+        ];
+        let merged: Vec<Equation> = merge_systems(&a, &b);
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged[..2], a[..]);
+        assert_eq!(merged[2], b[2]);
+        assert_eq!(merge_systems(&a, &a), a);
+
+        // Values that render the same are still different constraints
+        let c: Vec<Equation> = vec![Equation::new(t("x"), Value(1234567.0))];
+        let d: Vec<Equation> = vec![Equation::new(t("x"), Value(1234999.0))];
+        assert_eq!(merge_systems(&c, &d).len(), 2);
+        let c: Vec<Equation> = vec![Equation::new(t("x"), Value(0.10001))];
+        let d: Vec<Equation> = vec![Equation::new(t("x"), Value(0.10004))];
+        assert_eq!(merge_systems(&c, &d).len(), 2);
+    }
+
+    #[test]
+    fn test_solve_errors() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let t = |x: &str| Text(x.to_string());
+        // 2x + 1 = 7
+        let linear: Equation = Equation::new(
+            Sum(vec![Multiply(vec![Value(2.0), t("x")]), Value(1.0)]),
+            Value(7.0),
+        );
+        assert_eq!(linear.solve(), Ok(3.0));
+        assert_eq!(linear.solve_for("x"), Ok(3.0));
+        let square: Equation = Equation::new(Power(boxed(t("x")), boxed(Value(2.0))), Value(4.0));
+        assert_eq!(square.solve(), Err(SolveError::Nonlinear));
+        let two: Equation = Equation::new(t("x"), t("y"));
+        assert_eq!(
+            two.solve(),
+            Err(SolveError::MultipleUnknowns(vec![
+                "x".to_string(),
+                "y".to_string()
+            ]))
+        );
+        assert_eq!(
+            linear.solve_for("y"),
+            Err(SolveError::MultipleUnknowns(vec![
+                "x".to_string(),
+                "y".to_string()
+            ]))
+        );
+        // Without an unknown the equation is either false or always true
+        let none: Equation = Equation::new(Value(1.0), Value(2.0));
+        assert_eq!(none.solve(), Err(SolveError::NoSolution));
+        let always: Equation = Equation::new(Value(1.0), Value(1.0));
+        assert_eq!(always.solve(), Err(SolveError::InfiniteSolutions));
+        assert_eq!(
+            Equation::new(t("x"), Sum(vec![t("x"), Value(1.0)])).solve(),
+            Err(SolveError::NoSolution)
+        );
+        assert_eq!(
+            Equation::new(
+                Multiply(vec![Value(2.0), t("x")]),
+                Sum(vec![t("x"), t("x")])
+            )
+            .solve(),
+            Err(SolveError::InfiniteSolutions)
+        );
+        let infinite: Operation = Divide(boxed(Value(1.0)), boxed(Value(0.0)));
+        assert_eq!(
+            Equation::new(t("x"), infinite).solve(),
+            Err(SolveError::DivisionByZero)
+        );
+        // Overflowing is not a division by zero
+        let huge: Operation = Multiply(vec![Value(1e300), Value(1e300)]);
+        assert_eq!(
+            Equation::new(Multiply(vec![t("x"), huge.clone()]), huge).solve(),
+            Err(SolveError::NoSolution)
+        );
+        // Variables with a value are not unknowns
+        let bound: Equation = Equation::new(
+            Multiply(vec![Operation::variable_with_value("k", 4.0), t("x")]),
+            Value(2.0),
+        );
+        assert_eq!(bound.solve(), Ok(0.5));
+        assert_eq!(
+            EquationSolver::simplify(&linear),
+            Ok(Equation::new(
+                Sum(vec![Multiply(vec![Value(2.0), t("x")]), Value(1.0)]),
+                Value(7.0)
+            ))
+        );
+    }
 }
@@ -1,11 +1,13 @@
+use crate::mappings::{capture, RewriteRule};
 use crate::math::EquationMember;
 use crate::prelude::*;
+use num_complex::Complex;
 use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
 use std::hash::{Hash, Hasher};
 use std::ops::{Add, Index};
-use std::ptr::hash;
-use std::rc::Rc;
 
 #[derive(Clone)]
 pub enum Operation {
@@ -17,18 +19,30 @@ pub enum Operation {
     Text(String),
     Mapping(usize),
     Equal(Option<Box<Operation>>, Option<Box<Operation>>),
-    Variable(Rc<dyn EquationMember>),
-    Display(Rc<dyn EquationMember>),
+    Variable(SharedMember),
+    Display(SharedMember),
     Power(Option<Box<Operation>>, Option<Box<Operation>>),
+    /// An exact fraction stored as `(numerator, denominator)`, see `Operation::rational`.
+    /// Fractions built directly instead of through `Operation::rational` are brought to
+    /// lowest terms by `simplify`.
+    Rational(i64, i64),
+    /// `(condition, value)` branches and an optional default. The value of the first
+    /// branch whose condition holds is used, see `Operation::piecewise_branch`. An `Equal`
+    /// condition holds when its sides are equal, any other when it is nonzero.
+    Piecewise(Vec<(Operation, Operation)>, Option<Box<Operation>>),
+    /// The natural logarithm of its operand.
+    Log(Option<Box<Operation>>),
 }
 
 impl EquationMember for Operation {
     fn equation_repr(&self) -> String {
         match self {
+            Multiply(list) if list.is_empty() => "1".to_string(),
+            Sum(list) if list.is_empty() => "0".to_string(),
             Multiply(list) => {
                 let mut string = String::new();
                 for (i, item) in list.iter().enumerate() {
-                    string.push_str(&item.equation_repr());
+                    string.push_str(&grouped_repr(item, self.precedence()));
                     if i != list.len() - 1 {
                         string.push_str(" * ");
                     }
@@ -36,45 +50,37 @@ impl EquationMember for Operation {
                 string
             }
             Negate(a) => {
-                match a {
-                    Some(op) => match op.as_ref() {
-                        Negate(Some(x)) => {
-                            return format!("{}", x.equation_repr());
-                        }
-                        _ => {}
-                    },
-                    None => {}
+                if let Some(Negate(Some(x))) = a.as_deref() {
+                    return x.equation_repr();
                 }
 
-                format!("-{}", a.clone().unwrap().equation_repr())
+                format!("-{}", grouped_repr(a.as_ref().unwrap(), self.precedence()))
             }
             Divide(Some(a), Some(b)) => {
-                let mut numerator = a.equation_repr();
-                let mut denominator = b.equation_repr();
-                match *a.clone() {
-                    Multiply(a) | Sum(a) => {
-                        if a.len() > 1 {
-                            numerator = "{".to_owned() + numerator.as_str() + "}";
-                        }
-                    }
-                    _ => {}
-                }
-                match *b.clone() {
-                    Multiply(a) | Sum(a) => {
-                        if a.len() > 1 {
-                            denominator = "{".to_owned() + denominator.as_str() + "}";
-                        }
-                    }
-                    _ => {}
-                }
+                // Products are grouped too, a/b * c would read as (a/b) * c
+                let numerator: String = grouped_repr(a, self.precedence() + 1);
+                let denominator: String = grouped_repr(b, self.precedence() + 1);
                 format!("{}/{}", numerator, denominator)
             }
             Sum(vec) => {
                 let mut string = String::new();
+                // Double negations render as the plain term
+                let negated =
+                    |x: &Operation| matches!(x, Negate(Some(a)) if !matches!(**a, Negate(_)));
                 for (i, item) in vec.iter().enumerate() {
-                    string.push_str(&item.equation_repr());
+                    match item {
+                        // The sign is written by the separator, as in latex_string
+                        Negate(Some(a)) if i != 0 && negated(item) => {
+                            string.push_str(&grouped_repr(a, self.precedence() + 1))
+                        }
+                        _ => string.push_str(&grouped_repr(item, self.precedence())),
+                    }
                     if i != vec.len() - 1 {
-                        string.push_str(" + ");
+                        if vec.get(i + 1).is_some_and(negated) {
+                            string.push_str(" - ");
+                        } else {
+                            string.push_str(" + ");
+                        }
                     }
                 }
                 string
@@ -88,7 +94,24 @@ impl EquationMember for Operation {
             Variable(a) => a.equation_repr(),
             Display(a) => a.equation_repr(),
             Power(Some(a), Some(b)) => {
-                format!("{}^{}", a.equation_repr(), b.equation_repr())
+                // Powers are right associative, (a^b)^c needs its base grouped
+                format!(
+                    "{}^{}",
+                    grouped_repr(a, self.precedence() + 1),
+                    grouped_repr(b, self.precedence())
+                )
+            }
+            Rational(n, d) => format!("{}/{}", n, d),
+            Log(Some(a)) => format!("ln({})", a.equation_repr()),
+            Piecewise(branches, default) => {
+                let mut parts: Vec<String> = branches
+                    .iter()
+                    .map(|(c, v)| format!("{} if {}", v.equation_repr(), c.equation_repr()))
+                    .collect();
+                if let Some(default) = default {
+                    parts.push(format!("{} otherwise", default.equation_repr()));
+                }
+                format!("{{{}}}", parts.join(", "))
             }
             _ => {
                 panic!("Not implemented");
@@ -106,6 +129,7 @@ impl EquationMember for Operation {
                 product
             }
             Negate(Some(a)) => -a.value(),
+            Log(Some(a)) => a.value().ln(),
             Sum(vec) => {
                 let mut sum = 0.0;
                 for item in vec {
@@ -114,148 +138,76 @@ impl EquationMember for Operation {
                 sum
             }
             Divide(Some(a), Some(b)) => a.value() / b.value(),
+            Power(Some(a), Some(b)) => a.value().powf(b.value()),
+            Rational(n, d) => *n as f64 / *d as f64,
+            Piecewise(_, _) => self.piecewise_branch().map_or(f64::NAN, |x| x.value()),
             Value(a) => a.value(),
-            Mapping(_) | Text(_) => 1.0,
+            Text(_) => 1.0,
             Variable(a) => a.value(),
+            Mapping(a) => panic!("Mapping({}) is a placeholder and has no value", a),
             _ => {
                 panic!("Not implemented");
             }
         }
     }
 
-    /// Simplifies the operation, returning `Some(_)` new operation if possible.
-    /// returning `None` if the operation cannot be simplified.
+    /// Simplifies the operation, see `Operation::simplify_with` for the rules applied.
+    /// Uses the default `SimplifyMode::Numeric`.
     fn simplify(&self) -> Option<Operation> {
-        match self {
-            Multiply(list) => {
-                let mut coefficient: f64 = 1.0;
-                let mut result: Vec<Operation> = Vec::new();
-                list.iter().for_each(|x| match x {
-                    Value(a) => coefficient *= a.value(),
-                    Mapping(_) | Text(_) => result.push(x.clone()),
-                    _ => {
-                        if let Some(child_simplification) = x.simplify() {
-                            coefficient *= child_simplification.value();
-                        } else {
-                            result.push(x.clone());
-                        }
-                    }
-                });
-                result.push(Value(coefficient));
-                if result.len() == 1 {
-                    return Some(result[0].clone());
-                }
-                return Some(Multiply(result));
-            }
-            Sum(list) => {
-                let mut total: f64 = 0.0;
-                let mut result: Vec<Operation> = Vec::new();
-                list.iter().for_each(|x| match x {
-                    Value(a) => total += a.value(),
-                    Mapping(_) | Text(_) | Variable(_) => result.push(x.clone()),
-                    Sum(vec) => {
-                        result.extend(vec.iter().cloned());
-                    }
-                    _ => {
-                        if let Some(child_simplification) = x.simplify() {
-                            total += child_simplification.value();
-                        } else {
-                            result.push(x.clone());
-                        }
-                    }
-                });
-                if total != 0.0 {
-                    result.push(Value(total));
-                }
-                if result.len() == 1 {
-                    return Some(result[0].clone());
-                }
-                return Some(Sum(result));
-            }
-            Negate(Some(child)) => match child.as_ref() {
-                Negate(second_child) => {
-                    if let Some(second_child) = second_child {
-                        return Some(*second_child.clone());
-                    }
-                }
-                Value(a) => return Some(Value(-a.value())),
-                Sum(vec) => {
-                    let mut result: Vec<Operation> = Vec::new();
-                    for item in vec {
-                        result.push(Negate(Some(Box::new(item.clone()))));
-                    }
-                    return Some(Sum(result));
-                }
-                _ => {
-                    let result = child.simplify();
-                    if let Some(result) = result {
-                        if let Value(a) = result {
-                            return Some(Value(-a.value()));
-                        }
-                        if let Negate(Some(x)) = result {
-                            return Some(*x);
-                        }
-                        return Some(Negate(Some(Box::new(result))));
-                    }
-                }
-            },
-            Divide(Some(numerator), Some(divisor)) => {
-                let simplification: (Option<Operation>, Option<Operation>) =
-                    (numerator.simplify(), divisor.simplify());
-                if let (Some(Value(a)), Some(Value(b))) = (&simplification.0, &simplification.1) {
-                    return Some(Value(a.value() / b.value()));
-                }
-                if let (None, None) = simplification {
-                    return None;
-                }
-                let a = simplification.0.unwrap_or_else(|| *numerator.clone());
-                let b = simplification.1.unwrap_or_else(|| *divisor.clone());
-                return Some(Divide(Some(Box::new(a)), Some(Box::new(b))));
-            }
-            Equal(Some(ls), Some(rs)) => {
-                let simplification: (Option<Operation>, Option<Operation>) =
-                    (ls.simplify(), rs.simplify());
-                if let (None, None) = simplification {
-                    return None;
-                }
-                let a = simplification.0.unwrap_or_else(|| *ls.clone());
-                let b = simplification.1.unwrap_or_else(|| *rs.clone());
-                return Some(Equal(Some(Box::new(a)), Some(Box::new(b))));
-            }
-            Value(_) => return Some(self.clone()),
-            _ => {}
-        }
-
-        None
+        self.simplify_with(SimplifyMode::default())
     }
 
     fn latex_string(&self) -> String {
+        self.latex_string_with(LatexStyle::default())
+    }
+}
+
+impl Operation {
+    /// Renders the operation to LaTeX, choosing the notation with `style`.
+    ///
+    /// `latex_string` uses the default style.
+    pub fn latex_string_with(&self, style: LatexStyle) -> String {
+        let grouped = |x: &Operation, precedence: u8| {
+            if needs_grouping(x, precedence) {
+                format!("({})", x.latex_string_with(style))
+            } else {
+                x.latex_string_with(style)
+            }
+        };
         match self {
+            Multiply(list) if list.is_empty() => "1".to_string(),
+            Sum(list) if list.is_empty() => "0".to_string(),
             Multiply(list) => {
                 let mut string = String::new();
                 for (i, item) in list.iter().enumerate() {
-                    string.push_str(&item.latex_string());
-                    if i != list.len() - 1 {
-                        string.push_str(" \\cdot ");
+                    string.push_str(&grouped(item, self.precedence()));
+                    match (item, list.get(i + 1)) {
+                        (_, None) => {}
+                        // Coefficients are juxtaposed with symbols, e.g. 2x
+                        (Value(_), Some(next)) if is_symbolic_factor(next) => {}
+                        _ => string.push_str(" \\cdot "),
                     }
                 }
                 string
             }
-            Negate(Some(a)) => format!("-{{{}}}", a.latex_string()),
+            Negate(Some(a)) if needs_grouping(a, self.precedence()) => {
+                format!("-{}", grouped(a, self.precedence()))
+            }
+            Negate(Some(a)) => format!("-{{{}}}", a.latex_string_with(style)),
             Sum(vec) => {
                 let mut string = String::from("{");
                 for (i, item) in vec.iter().enumerate() {
                     if let Sum(_) | Multiply(_) = item {
-                        string.push_str("{");
+                        string.push('{');
                     }
                     if let Negate(Some(a)) = item {
                         if i != 0 {
-                            string.push_str(&a.latex_string());
+                            string.push_str(&grouped(a, self.precedence() + 1));
                         } else {
-                            string.push_str(&item.latex_string());
+                            string.push_str(&item.latex_string_with(style));
                         }
                     } else {
-                        string.push_str(&item.latex_string());
+                        string.push_str(&item.latex_string_with(style));
                     }
                     if i != vec.len() - 1 {
                         if let Some(Negate(_)) = vec.get(i + 1) {
@@ -265,40 +217,126 @@ impl EquationMember for Operation {
                         }
                     }
                     if let Sum(_) | Multiply(_) = item {
-                        string.push_str("}");
+                        string.push('}');
                     }
                 }
-                string.push_str("}");
+                string.push('}');
                 string
             }
-            Divide(Some(a), Some(b)) => {
-                format!("\\frac{{{}}}{{{}}}", a.latex_string(), b.latex_string())
-            }
-            Equal(Some(a), Some(b)) => format!("{} = {}", a.latex_string(), b.latex_string()),
+            Divide(Some(a), Some(b)) => match style.fractions {
+                FractionStyle::Frac => format!(
+                    "\\frac{{{}}}{{{}}}",
+                    a.latex_string_with(style),
+                    b.latex_string_with(style)
+                ),
+                FractionStyle::Slash => format!(
+                    "{}/{}",
+                    grouped(a, self.precedence() + 1),
+                    grouped(b, self.precedence() + 1)
+                ),
+            },
+            Equal(Some(a), Some(b)) => format!(
+                "{} = {}",
+                a.latex_string_with(style),
+                b.latex_string_with(style)
+            ),
             Value(a) => a.latex_string(),
             Mapping(a) => a.latex_string(),
             Variable(a) => a.latex_string(),
             Text(a) => format!("${}$", a),
             Display(a) => a.latex_string(),
-            Power(Some(a), Some(b)) => format!("{}^{{{}}}", a.latex_string(), b.latex_string()),
+            // The exponent is raised, so only the base can need grouping
+            Power(Some(a), Some(b)) => format!(
+                "{}^{{{}}}",
+                grouped(a, self.precedence() + 1),
+                b.latex_string_with(style)
+            ),
+            Rational(n, d) => match style.fractions {
+                FractionStyle::Frac => format!("\\frac{{{}}}{{{}}}", n, d),
+                FractionStyle::Slash => format!("{}/{}", n, d),
+            },
+            Log(Some(a)) => format!("\\ln({})", a.latex_string_with(style)),
+            Piecewise(branches, default) => {
+                let mut lines: Vec<String> = branches
+                    .iter()
+                    .map(|(c, v)| {
+                        format!(
+                            "{} & {}",
+                            v.latex_string_with(style),
+                            c.latex_string_with(style)
+                        )
+                    })
+                    .collect();
+                if let Some(default) = default {
+                    lines.push(format!(
+                        "{} & \\text{{otherwise}}",
+                        default.latex_string_with(style)
+                    ));
+                }
+                format!("\\begin{{cases}}{}\\end{{cases}}", lines.join("\\\\"))
+            }
             _ => "$Not implemented$".to_string(),
         }
     }
-}
 
-impl Operation {
     /// Checks if the operation matches the given operation.
     /// Text (Variable) and Value operations are considered to match each other.
+    ///
+    /// This only compares the top level node types, a `Mapping` matches any leaf. Use
+    /// `compare_structure` to match a whole template, and `==` to compare mappings by index.
     pub fn matches(&self, rs: &Operation) -> bool {
-        match (self, rs) {
-            (Sum(_), Sum(_)) => true,
-            (Multiply(_), Multiply(_)) => true,
-            (Negate(_), Negate(_)) => true,
-            (Divide(_, _), Divide(_, _)) => true,
-            (Mapping(_), Mapping(_)) => true,
-            (Value(_) | Text(_) | Mapping(_), Value(_) | Text(_) | Mapping(_)) => true,
-            (Equal(_, _), Equal(_, _)) => true,
-            _ => false,
+        matches!(
+            (self, rs),
+            (Sum(_), Sum(_))
+                | (Multiply(_), Multiply(_))
+                | (Negate(_), Negate(_))
+                | (Log(_), Log(_))
+                | (Divide(_, _), Divide(_, _))
+                | (Mapping(_), Mapping(_))
+                | (
+                    Value(_) | Text(_) | Mapping(_),
+                    Value(_) | Text(_) | Mapping(_)
+                )
+                | (Equal(_, _), Equal(_, _))
+                | (Power(_, _), Power(_, _))
+                | (Piecewise(_, _), Piecewise(_, _))
+        )
+    }
+
+    /// Creates a named `Variable` without a value.
+    ///
+    /// This is the `Variable` counterpart of `Text`, the symbol renders as `name` and is left
+    /// untouched by `apply_variables` until it is replaced by a bound variable.
+    pub fn variable(name: &str) -> Operation {
+        Variable(Shared::new(EquationRepr::new(name.to_string(), f64::NAN)))
+    }
+
+    /// Creates a named `Variable` bound to `value`.
+    pub fn variable_with_value(name: &str, value: f64) -> Operation {
+        Variable(Shared::new(EquationRepr::new(name.to_string(), value)))
+    }
+
+    /// Returns true if every `Value` and `Rational` in the tree is finite.
+    ///
+    /// Symbols are not evaluated, so `x / 0` is finite while `x / Value(f64::INFINITY)` is not.
+    pub fn is_finite(&self) -> bool {
+        let mut finite: bool = true;
+        self.walk(&mut |x| match x {
+            Value(a) => finite &= a.is_finite(),
+            Rational(_, d) => finite &= *d != 0,
+            _ => {}
+        });
+        finite
+    }
+
+    /// Creates an exact fraction reduced to lowest terms with a positive denominator.
+    ///
+    /// Falls back to a `Value` if the reduced fraction does not fit in an `i64`, e.g.
+    /// `i64::MIN / -1`. Panics if the denominator is zero.
+    pub fn rational(numerator: i64, denominator: i64) -> Operation {
+        match rational_or_value(numerator, denominator) {
+            Some(a) => a,
+            None => panic!("Rational denominator cannot be zero"),
         }
     }
 
@@ -311,7 +349,7 @@ impl Operation {
         result
     }
 
-    pub fn get_child(&self) -> Option<Rc<dyn EquationMember>> {
+    pub fn get_child(&self) -> Option<SharedMember> {
         match self {
             Variable(a) => Some(a.clone()),
             _ => None,
@@ -331,28 +369,30 @@ impl Operation {
                     prelim.extend(item.get_variables());
                 }
             }
-            Negate(Some(a)) => {
+            Negate(Some(a)) | Log(Some(a)) => {
                 prelim.extend(a.get_variables());
             }
-            Divide(Some(a), Some(b)) => {
+            Divide(Some(a), Some(b)) | Equal(Some(a), Some(b)) | Power(Some(a), Some(b)) => {
                 prelim.extend(a.get_variables());
                 prelim.extend(b.get_variables());
             }
-            Equal(Some(a), Some(b)) => {
-                prelim.extend(a.get_variables());
-                prelim.extend(b.get_variables());
+            Variable(_) | Text(_) => {
+                prelim.push(self.clone());
             }
-            Variable(a) => {
-                prelim.push(Variable(a.clone()));
+            Piecewise(_, _) => {
+                for item in self.children() {
+                    prelim.extend(item.get_variables());
+                }
             }
             _ => {}
         }
 
         // TODO: This function is not complete, its very inefficient.
+        // Symbols are compared by name so `Text("x")` and `Operation::variable("x")` are the same.
         let mut out: Vec<Operation> = Vec::new();
         'outer: for item in prelim {
             for x in &out {
-                if x.latex_string() == item.latex_string() {
+                if x.equation_repr() == item.equation_repr() {
                     continue 'outer;
                 }
             }
@@ -361,6 +401,47 @@ impl Operation {
         out
     }
 
+    /// Returns the distinct names of the symbols in the tree, sorted lexicographically.
+    ///
+    /// Unlike `get_variables` the result does not depend on the order the tree was built
+    /// in, so it can be passed to `linear_coefficients` for a stable column order.
+    pub fn sorted_variable_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .get_variables()
+            .iter()
+            .map(|x| x.equation_repr())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Partitions the sorted symbol names into `(parameters, unknowns)`, where parameters
+    /// are the names listed in `params`.
+    pub fn classify_variables(&self, params: &HashSet<String>) -> (Vec<String>, Vec<String>) {
+        self.sorted_variable_names()
+            .into_iter()
+            .partition(|x| params.contains(x))
+    }
+
+    /// Returns the sorted names of the symbols that would remain after replacing each symbol
+    /// named in `substitutions` by its expression once.
+    ///
+    /// Symbols introduced by a substitution are included, even if they are themselves keys
+    /// of `substitutions`, since the replacement is not applied recursively.
+    pub fn free_variables_after(&self, substitutions: &HashMap<String, Operation>) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .sorted_variable_names()
+            .into_iter()
+            .flat_map(|x| match substitutions.get(&x) {
+                Some(a) => a.sorted_variable_names(),
+                None => vec![x],
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
     pub fn apply_variables(&mut self) -> &mut Self {
         match self {
             Sum(vec) => {
@@ -373,14 +454,10 @@ impl Operation {
                     item.apply_variables();
                 }
             }
-            Negate(Some(a)) => {
-                a.apply_variables();
-            }
-            Divide(Some(a), Some(b)) => {
+            Negate(Some(a)) | Log(Some(a)) => {
                 a.apply_variables();
-                b.apply_variables();
             }
-            Equal(Some(a), Some(b)) => {
+            Divide(Some(a), Some(b)) | Equal(Some(a), Some(b)) | Power(Some(a), Some(b)) => {
                 a.apply_variables();
                 b.apply_variables();
             }
@@ -399,7 +476,7 @@ impl Operation {
     pub fn contains_variable(&self, rs: Operation) -> bool {
         match self {
             Multiply(list) | Sum(list) => list.iter().any(|x| x.contains_variable(rs.clone())),
-            Negate(Some(a)) => a.contains_variable(rs),
+            Negate(Some(a)) | Log(Some(a)) => a.contains_variable(rs),
             Divide(Some(a), Some(b)) | Equal(Some(a), Some(b)) => {
                 a.contains_variable(rs.clone()) || b.contains_variable(rs)
             }
@@ -407,19 +484,27 @@ impl Operation {
         }
     }
 
+    /// Returns true if the symbol named `var` does not appear anywhere in the tree.
+    ///
+    /// Symbols are compared by name, so a `Text` and a `Variable` with the same name are the
+    /// same symbol, unlike `contains_variable` which compares whole operations.
+    pub fn is_constant_in(&self, var: &str) -> bool {
+        !self
+            .get_variables()
+            .iter()
+            .any(|x| x.equation_repr() == var)
+    }
+
     /// Extracts the coefficient of an operation.
     ///
     /// NOTE The actual value is most certainly different than this result.
+    ///
+    /// Returns `None` for a division containing a `Mapping`, which has no value.
     pub fn get_coefficient(&self) -> Option<f64> {
         match self {
+            Divide(_, _) if self.is_template() => None,
             Value(a) => Some(a.value()),
-            Negate(Some(a)) => {
-                if let Some(value) = a.get_coefficient() {
-                    Some(-value)
-                } else {
-                    None
-                }
-            }
+            Negate(Some(a)) => a.get_coefficient().map(|value| -value),
             Multiply(list) => {
                 let mut coefficient: f64 = 1.0;
                 for item in list {
@@ -443,361 +528,5716 @@ impl Operation {
         }
     }
 
-    pub fn print_operation_type(&self) -> &str {
+    /// Extracts the linear form of the operation with respect to `vars`.
+    ///
+    /// Returns the coefficient of each variable, in the order of `vars`, along with the
+    /// constant term. Returns `None` if the operation is not linear in `vars`, divides by a
+    /// constant zero or contains symbols without a value that are not listed in `vars`.
+    pub fn linear_coefficients(&self, vars: &[String]) -> Option<(Vec<f64>, f64)> {
         match self {
-            Multiply(_) => "Multiply",
-            Negate(_) => "Negate",
-            Sum(_) => "Sum",
-            Divide(_, _) => "Divide",
-            Equal(_, _) => "Equal",
-            Value(_) => "Value",
-            Mapping(_) => "Mapping",
-            Text(_) => "Text",
-            Variable(_) => "Variable",
-            Display(_) => "Display",
-            Power(_, _) => "Power",
-        }
-    }
-
-    pub fn compare_structure(&self, rs: &Operation) -> bool {
-        match (self, rs) {
-            (Sum(ls), Sum(rs)) | (Multiply(ls), Multiply(rs)) => {
-                if ls.len() != rs.len() {
-                    return false;
+            Value(_) | Rational(_, _) => Some((vec![0.0; vars.len()], self.value())),
+            Text(_) | Variable(_) => {
+                let name: String = self.equation_repr();
+                let mut coefficients: Vec<f64> = vec![0.0; vars.len()];
+                if let Some(index) = vars.iter().position(|x| *x == name) {
+                    coefficients[index] = 1.0;
+                    return Some((coefficients, 0.0));
                 }
-                for (l, r) in ls.iter().zip(rs.iter()) {
-                    if !l.compare_structure(r) {
-                        return false;
+                match self.get_child() {
+                    Some(a) if a.value().is_finite() => Some((coefficients, a.value())),
+                    _ => None,
+                }
+            }
+            Negate(Some(a)) => {
+                let (coefficients, constant) = a.linear_coefficients(vars)?;
+                Some((coefficients.iter().map(|x| -x).collect(), -constant))
+            }
+            Sum(list) => {
+                let mut coefficients: Vec<f64> = vec![0.0; vars.len()];
+                let mut constant: f64 = 0.0;
+                for item in list {
+                    let (item_coefficients, item_constant) = item.linear_coefficients(vars)?;
+                    coefficients
+                        .iter_mut()
+                        .zip(item_coefficients)
+                        .for_each(|(x, y)| *x += y);
+                    constant += item_constant;
+                }
+                Some((coefficients, constant))
+            }
+            Multiply(list) => {
+                let mut coefficients: Vec<f64> = vec![0.0; vars.len()];
+                let mut constant: f64 = 1.0;
+                for item in list {
+                    let (item_coefficients, item_constant) = item.linear_coefficients(vars)?;
+                    let linear: bool = coefficients.iter().any(|x| *x != 0.0);
+                    let item_linear: bool = item_coefficients.iter().any(|x| *x != 0.0);
+                    if linear && item_linear {
+                        return None;
                     }
+                    coefficients = coefficients
+                        .iter()
+                        .zip(item_coefficients)
+                        .map(|(x, y)| x * item_constant + y * constant)
+                        .collect();
+                    constant *= item_constant;
                 }
-                true
+                Some((coefficients, constant))
             }
-            (Negate(Some(ls)), Negate(Some(rs))) => ls.compare_structure(rs),
-            (Negate(Some(ls)), _) => ls.compare_structure(rs),
-            (_, Negate(Some(rs))) => rs.compare_structure(self),
-            (Divide(Some(lsn), Some(lsd)), Divide(Some(rsn), Some(rsd))) => {
-                let denominator: bool = lsd.compare_structure(rsd);
-                let numerator_match: bool = lsn.compare_structure(rsn);
-                denominator && numerator_match
+            Divide(Some(a), Some(b)) => {
+                let (coefficients, constant) = a.linear_coefficients(vars)?;
+                let (divisor_coefficients, divisor) = b.linear_coefficients(vars)?;
+                if divisor == 0.0 || divisor_coefficients.iter().any(|x| *x != 0.0) {
+                    return None;
+                }
+                Some((
+                    coefficients.iter().map(|x| x / divisor).collect(),
+                    constant / divisor,
+                ))
             }
-            (_, Mapping(_)) | (Mapping(_), _) => true,
-            (a, b) => a.matches(b),
+            Power(Some(a), Some(b)) => {
+                let (coefficients, constant) = a.linear_coefficients(vars)?;
+                let (exponent_coefficients, exponent) = b.linear_coefficients(vars)?;
+                if exponent_coefficients.iter().any(|x| *x != 0.0) {
+                    return None;
+                }
+                if coefficients.iter().all(|x| *x == 0.0) {
+                    Some((coefficients, constant.powf(exponent)))
+                } else if exponent == 1.0 {
+                    Some((coefficients, constant))
+                } else {
+                    None
+                }
+            }
+            Log(Some(a)) => {
+                let (coefficients, constant) = a.linear_coefficients(vars)?;
+                if coefficients.iter().any(|x| *x != 0.0) {
+                    return None;
+                }
+                Some((coefficients, constant.ln()))
+            }
+            Equal(Some(a), Some(b)) => {
+                Sum(vec![*a.clone(), Negate(Some(b.clone()))]).linear_coefficients(vars)
+            }
+            _ => None,
         }
     }
 
-    pub fn cleanup(&mut self) {
+    /// Returns the total degree of the operation as a polynomial in `vars`, e.g. 2 for
+    /// `x * y + x`.
+    ///
+    /// Terms are not collected, so the degree of a sum is that of its highest term even if
+    /// it cancels. Returns `None` if the operation is not a polynomial in `vars`, e.g. when one
+    /// of them appears in a divisor or an exponent. The sides of an `Equal` are both counted.
+    pub fn polynomial_degree(&self, vars: &[String]) -> Option<usize> {
+        let constant = |x: &Operation| vars.iter().all(|var| x.is_constant_in(var));
         match self {
-            Negate(Some(a)) => match *a.clone() {
-                Negate(Some(b)) => {
-                    *self = *b.clone();
+            _ if constant(self) => Some(0),
+            Text(_) | Variable(_) => Some(1),
+            Negate(Some(a)) => a.polynomial_degree(vars),
+            Sum(list) => list
+                .iter()
+                .try_fold(0, |degree, x| Some(degree.max(x.polynomial_degree(vars)?))),
+            Multiply(list) => list.iter().try_fold(0, |degree: usize, x| {
+                degree.checked_add(x.polynomial_degree(vars)?)
+            }),
+            Divide(Some(a), Some(b)) if constant(b) => a.polynomial_degree(vars),
+            Power(Some(a), Some(b)) if constant(b) => {
+                let exponent: f64 = b.evaluate(&HashMap::new()).ok()?;
+                if exponent < 0.0 || exponent.fract() != 0.0 {
+                    return None;
                 }
-                _ => a.cleanup(),
-            },
-            Sum(list) => list.iter_mut().for_each(|x| x.cleanup()),
-            _ => {}
+                a.polynomial_degree(vars)?.checked_mul(exponent as usize)
+            }
+            Equal(Some(a), Some(b)) => {
+                Some(a.polynomial_degree(vars)?.max(b.polynomial_degree(vars)?))
+            }
+            _ => None,
         }
     }
 
-    pub fn evaluate(&self) -> Option<f64> {
-        // TODO This `evaluate` function is not complete
-        panic!("Not implemented");
+    /// Collects the operation into a polynomial in `var`, returning the simplified
+    /// coefficient of each power of `var`, indexed by the power.
+    ///
+    /// Coefficients may contain other symbols. Returns `None` if the operation is not a
+    /// polynomial in `var`, e.g. when `var` appears in a divisor or an exponent, or if its
+    /// degree exceeds `MAX_POLYNOMIAL_DEGREE`. The difference of the sides of an `Equal` is
+    /// collected. Trailing zero coefficients are dropped, the zero polynomial is `[0]`.
+    pub fn as_polynomial(&self, var: &str) -> Option<Vec<Operation>> {
+        let mut coefficients: Vec<Operation> = polynomial_terms(self, var)?
+            .iter()
+            .map(|x| x.simplify_fully())
+            .collect();
+        while coefficients.len() > 1 && coefficients.last() == Some(&Value(0.0)) {
+            coefficients.pop();
+        }
+        Some(coefficients)
     }
-}
 
-impl Debug for Operation {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.equation_repr())
+    /// Solves the operation for `var` with the quadratic formula.
+    ///
+    /// The operation (or the difference of the sides of an `Equal`) must be a polynomial of
+    /// degree two in `var` with numeric coefficients, `None` is returned otherwise. The two
+    /// roots are returned unsimplified as `(-b + (b^2 - 4ac)^0.5) / 2a` and
+    /// `(-b - (b^2 - 4ac)^0.5) / 2a`, complex roots can be found with `evaluate_complex`.
+    pub fn solve_quadratic(&self, var: &str) -> Option<Vec<Operation>> {
+        let coefficients: Vec<f64> = self
+            .as_polynomial(var)?
+            .iter()
+            .map(|x| x.evaluate(&HashMap::new()).ok())
+            .collect::<Option<Vec<f64>>>()?;
+        let [c, b, a] = coefficients[..] else {
+            return None;
+        };
+        if a == 0.0 {
+            return None;
+        }
+        let boxed = |x: Operation| Some(Box::new(x));
+        let discriminant: Operation = Sum(vec![
+            Power(boxed(Value(b)), boxed(Value(2.0))),
+            Negate(boxed(Multiply(vec![Value(4.0), Value(a), Value(c)]))),
+        ]);
+        let root = Power(boxed(discriminant), boxed(Value(0.5)));
+        let denominator: Operation = Multiply(vec![Value(2.0), Value(a)]);
+        Some(vec![
+            Divide(
+                boxed(Sum(vec![Negate(boxed(Value(b))), root.clone()])),
+                boxed(denominator.clone()),
+            ),
+            Divide(
+                boxed(Sum(vec![Negate(boxed(Value(b))), Negate(boxed(root))])),
+                boxed(denominator),
+            ),
+        ])
     }
-}
 
-impl PartialEq for Operation {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Value(a), Value(b)) => a.value() == b.value(),
-            (Text(a), Text(b)) => a == b,
-            (Multiply(a), Multiply(b)) => a.iter().all(|x| b.contains(x)) && b.len() == a.len(),
-            (Negate(a), Negate(b)) => a == b,
-            (Divide(a, b), Divide(c, d)) => a == c && b == d,
-            (Sum(a), Sum(b)) => a.iter().all(|x| b.contains(x)) && b.len() == a.len(),
-            (Mapping(a), Mapping(b)) => a == b,
-            _ => false,
+    /// Simplifies the operation, returning `Some(_)` new operation if possible.
+    /// returning `None` if the operation cannot be simplified.
+    ///
+    /// Numeric folding never produces a non-finite `Value`. Divisions by zero and overflowing
+    /// powers are left symbolic, the values of a sum or product that would overflow are left
+    /// unfolded, and non-finite values already in the tree are kept as separate terms instead
+    /// of being folded into the other constants.
+    ///
+    /// Empty sums and products are normalized to `Value(0.0)` and `Value(1.0)`, which is also
+    /// the result when every term of a sum cancels.
+    ///
+    /// Incomplete operations are normalized by treating the missing operand as empty:
+    /// a missing numerator, negated term or side of an `Equal` is `Value(0.0)`, while a
+    /// missing divisor or exponent leaves the other operand unchanged.
+    ///
+    /// With `SimplifyMode::ExactOnly` a division of two values is only folded when the
+    /// result is an integer, so `2 / 3` is kept as a fraction while `6 / 3` becomes `2`.
+    pub fn simplify_with(&self, mode: SimplifyMode) -> Option<Operation> {
+        self.simplify_in(mode, None)
+    }
+
+    /// Simplifies the operation like `simplify_with`, looking up and recording every
+    /// subtree in `cache` when one is given, see `simplify_batch`.
+    fn simplify_in(
+        &self,
+        mode: SimplifyMode,
+        cache: Option<&mut SimplifyCache>,
+    ) -> Option<Operation> {
+        let Some(cache) = cache else {
+            return self.simplify_node(mode, None);
+        };
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        let key: u64 = hasher.finish();
+        if let Some((_, result)) = cache
+            .get(&key)
+            .and_then(|x| x.iter().find(|(x, _)| x == self))
+        {
+            return result.clone();
         }
+        let result: Option<Operation> = self.simplify_node(mode, Some(cache));
+        cache
+            .entry(key)
+            .or_default()
+            .push((self.clone(), result.clone()));
+        result
     }
-}
 
-impl Hash for Operation {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        hash(self, state);
+    /// Applies the rules of `simplify_with` to the operation, simplifying its children
+    /// through `simplify_in`.
+    fn simplify_node(
+        &self,
+        mode: SimplifyMode,
+        mut cache: Option<&mut SimplifyCache>,
+    ) -> Option<Operation> {
+        match self {
+            Multiply(list) => {
+                if let Some(exact) = fold_rationals(list, multiply_rationals) {
+                    return Some(exact);
+                }
+                if let Some(quotient) = fold_reciprocals(list, mode) {
+                    return Some(quotient);
+                }
+                let mut values: Vec<f64> = Vec::new();
+                let mut result: Vec<Operation> = Vec::new();
+                combine_powers(list).iter().for_each(|x| match x {
+                    Value(a) if a.is_finite() => values.push(*a),
+                    Value(_) | Mapping(_) | Text(_) => result.push(x.clone()),
+                    _ => match x.simplify_in(mode, cache.as_deref_mut()) {
+                        Some(Value(a)) if a.is_finite() => values.push(a),
+                        Some(child_simplification) => result.push(child_simplification),
+                        None => result.push(x.clone()),
+                    },
+                });
+                let coefficient: f64 = if values.contains(&0.0) {
+                    0.0
+                } else {
+                    values.iter().product()
+                };
+                // Keep the factors apart rather than overflowing to infinity
+                if !coefficient.is_finite() {
+                    result.splice(0..0, values.into_iter().map(Value));
+                    return Some(Multiply(result));
+                }
+                // 0 * x = 0, unless x is infinite or NaN
+                if coefficient == 0.0 && !result.iter().any(|x| matches!(x, Value(_))) {
+                    return Some(Value(0.0));
+                }
+                if coefficient != 1.0 || result.is_empty() {
+                    result.insert(0, Value(coefficient));
+                }
+                if result.len() == 1 {
+                    return Some(result[0].clone());
+                }
+                return Some(Multiply(result));
+            }
+            Sum(list) => {
+                if let Some(exact) = fold_rationals(list, add_rationals) {
+                    return Some(exact);
+                }
+                let mut values: Vec<f64> = Vec::new();
+                let mut result: Vec<Operation> = Vec::new();
+                list.iter().for_each(|x| match x {
+                    Value(a) if a.is_finite() => values.push(*a),
+                    Value(_) | Mapping(_) | Text(_) | Variable(_) => result.push(x.clone()),
+                    Sum(vec) => {
+                        result.extend(vec.iter().cloned());
+                    }
+                    _ => match x.simplify_in(mode, cache.as_deref_mut()) {
+                        Some(Value(a)) if a.is_finite() => values.push(a),
+                        Some(child_simplification) => result.push(child_simplification),
+                        None => result.push(x.clone()),
+                    },
+                });
+                cancel_inverses(&mut result);
+                let total: f64 = values.iter().sum();
+                if !total.is_finite() {
+                    // Keep the terms apart rather than overflowing to infinity
+                    result.extend(values.into_iter().map(Value));
+                } else if total != 0.0 {
+                    result.push(Value(total));
+                }
+                if result.is_empty() {
+                    return Some(Value(0.0));
+                }
+                if result.len() == 1 {
+                    return Some(result[0].clone());
+                }
+                return Some(Sum(result));
+            }
+            Negate(Some(child)) => match child.as_ref() {
+                Negate(second_child) => {
+                    if let Some(second_child) = second_child {
+                        return Some(*second_child.clone());
+                    }
+                }
+                Value(a) => return Some(Value(-a.value())),
+                Rational(n, d) => {
+                    if let Some(n) = n.checked_neg() {
+                        return Some(Rational(n, *d));
+                    }
+                }
+                // -(a / b) = (-a) / b
+                Divide(Some(numerator), Some(divisor)) => {
+                    let divide: Operation = Divide(
+                        Some(Box::new(Negate(Some(numerator.clone())))),
+                        Some(divisor.clone()),
+                    );
+                    return Some(
+                        divide
+                            .simplify_in(mode, cache.as_deref_mut())
+                            .unwrap_or(divide),
+                    );
+                }
+                Sum(vec) => {
+                    let mut result: Vec<Operation> = Vec::new();
+                    for item in vec {
+                        result.push(Negate(Some(Box::new(item.clone()))));
+                    }
+                    return Some(Sum(result));
+                }
+                _ => {
+                    let result = child.simplify_in(mode, cache.as_deref_mut());
+                    if let Some(result) = result {
+                        if let Value(a) = result {
+                            return Some(Value(-a.value()));
+                        }
+                        if let Negate(Some(x)) = result {
+                            return Some(*x);
+                        }
+                        return Some(Negate(Some(Box::new(result))));
+                    }
+                }
+            },
+            Divide(Some(numerator), Some(divisor)) => {
+                let operands: [Operation; 2] = [*numerator.clone(), *divisor.clone()];
+                if let Some(exact) = fold_rationals(&operands, divide_rationals) {
+                    return Some(exact);
+                }
+                let simplification: (Option<Operation>, Option<Operation>) = (
+                    numerator.simplify_in(mode, cache.as_deref_mut()),
+                    divisor.simplify_in(mode, cache.as_deref_mut()),
+                );
+                if let (Some(Value(a)), Some(Value(b))) = (&simplification.0, &simplification.1) {
+                    let exact: bool = (a / b).fract() == 0.0;
+                    if (a / b).is_finite() && (exact || mode == SimplifyMode::Numeric) {
+                        return Some(Value(a.value() / b.value()));
+                    }
+                }
+                // x / 1 = x
+                if let Some(Value(b)) = simplification.1 {
+                    if b == 1.0 {
+                        return Some(simplification.0.unwrap_or_else(|| *numerator.clone()));
+                    }
+                }
+                // 0 / x = 0
+                if let (Some(Value(a)), b) = (&simplification.0, &simplification.1) {
+                    if *a == 0.0 && !matches!(b, Some(Value(b)) if *b == 0.0) {
+                        return Some(Value(0.0));
+                    }
+                }
+                // (6 * x) / (2 * y) = (3 * x) / y
+                let (a, numerator_base) = match &simplification.0 {
+                    Some(x) => x.decompose_term(),
+                    None => numerator.decompose_term(),
+                };
+                let (b, divisor_base) = match &simplification.1 {
+                    Some(x) => x.decompose_term(),
+                    None => divisor.decompose_term(),
+                };
+                let reduced: f64 = a / b;
+                if b != 1.0
+                    && reduced.is_finite()
+                    && !matches!(divisor_base, Value(_))
+                    && (reduced.fract() == 0.0 || mode == SimplifyMode::Numeric)
+                {
+                    let numerator: Operation = match numerator_base {
+                        Value(_) => Value(reduced),
+                        base if reduced == 1.0 => base,
+                        Multiply(mut factors) => {
+                            factors.insert(0, Value(reduced));
+                            Multiply(factors)
+                        }
+                        base => Multiply(vec![Value(reduced), base]),
+                    };
+                    return Some(Divide(
+                        Some(Box::new(numerator)),
+                        Some(Box::new(divisor_base)),
+                    ));
+                }
+                // x^a / x^b = x^(a - b)
+                if let (Power(Some(base), Some(a)), Power(Some(other), Some(b))) =
+                    (numerator.as_ref(), divisor.as_ref())
+                {
+                    if same_base(base, other) {
+                        let exponent: Operation = subtract_exponents(*a.clone(), *b.clone());
+                        return Some(Power(Some(base.clone()), Some(Box::new(exponent))));
+                    }
+                }
+                if let (None, None) = simplification {
+                    return None;
+                }
+                let a = simplification.0.unwrap_or_else(|| *numerator.clone());
+                let b = simplification.1.unwrap_or_else(|| *divisor.clone());
+                return Some(Divide(Some(Box::new(a)), Some(Box::new(b))));
+            }
+            Power(Some(base), Some(exponent)) => {
+                let simplification: (Option<Operation>, Option<Operation>) = (
+                    base.simplify_in(mode, cache.as_deref_mut()),
+                    exponent.simplify_in(mode, cache.as_deref_mut()),
+                );
+                if let (Some(Value(a)), Some(Value(b))) = (&simplification.0, &simplification.1) {
+                    if a.powf(*b).is_finite() {
+                        return Some(Value(a.powf(*b)));
+                    }
+                }
+                match (&simplification.0, &simplification.1) {
+                    // x^0 = 1
+                    (_, Some(Value(b))) if *b == 0.0 => return Some(Value(1.0)),
+                    // x^1 = x
+                    (_, Some(Value(b))) if *b == 1.0 => {
+                        return Some(simplification.0.unwrap_or_else(|| *base.clone()));
+                    }
+                    // 1^x = 1
+                    (Some(Value(a)), _) if *a == 1.0 => return Some(Value(1.0)),
+                    // 0^x = 0 only for a known positive x, 0^-x is a division by zero
+                    (Some(Value(a)), Some(Value(b))) if *a == 0.0 && *b > 0.0 => {
+                        return Some(Value(0.0));
+                    }
+                    // x^-n = 1 / x^n
+                    (_, Some(Value(b))) if *b < 0.0 && b.fract() == 0.0 => {
+                        let base: Operation = simplification.0.unwrap_or_else(|| *base.clone());
+                        let divisor: Operation = match -b {
+                            1.0 => base,
+                            n => Power(Some(Box::new(base)), Some(Box::new(Value(n)))),
+                        };
+                        return Some(Divide(Some(Box::new(Value(1.0))), Some(Box::new(divisor))));
+                    }
+                    _ => {}
+                }
+                // (x^a)^b = x^(a * b), except (x^2)^0.5 = |x| and the like
+                if let Power(Some(inner_base), Some(inner_exponent)) = base.as_ref() {
+                    let exponent: Operation =
+                        multiply_exponents(*inner_exponent.clone(), *exponent.clone());
+                    if !loses_sign(inner_exponent, &exponent) {
+                        return Some(Power(Some(inner_base.clone()), Some(Box::new(exponent))));
+                    }
+                }
+                // (a * b)^n = a^n * b^n and (a / b)^n = a^n / b^n for integer n
+                if let Some(Value(n)) = simplification.1.as_ref().or(Some(exponent)) {
+                    let power =
+                        |x: &Operation| Power(Some(Box::new(x.clone())), Some(Box::new(Value(*n))));
+                    let distributed: Option<Operation> =
+                        match simplification.0.as_ref().unwrap_or(base) {
+                            _ if n.fract() != 0.0 => None,
+                            Multiply(list) => Some(Multiply(list.iter().map(power).collect())),
+                            Divide(Some(a), Some(b)) => {
+                                Some(Divide(Some(Box::new(power(a))), Some(Box::new(power(b)))))
+                            }
+                            _ => None,
+                        };
+                    if let Some(distributed) = distributed {
+                        return Some(
+                            distributed
+                                .simplify_in(mode, cache.as_deref_mut())
+                                .unwrap_or(distributed),
+                        );
+                    }
+                }
+                if let (None, None) = simplification {
+                    return None;
+                }
+                let a = simplification.0.unwrap_or_else(|| *base.clone());
+                let b = simplification.1.unwrap_or_else(|| *exponent.clone());
+                if a == **base && b == **exponent {
+                    return None;
+                }
+                return Some(Power(Some(Box::new(a)), Some(Box::new(b))));
+            }
+            Equal(Some(ls), Some(rs)) => {
+                let simplification: (Option<Operation>, Option<Operation>) = (
+                    ls.simplify_in(mode, cache.as_deref_mut()),
+                    rs.simplify_in(mode, cache.as_deref_mut()),
+                );
+                if let (None, None) = simplification {
+                    return None;
+                }
+                let a = simplification.0.unwrap_or_else(|| *ls.clone());
+                let b = simplification.1.unwrap_or_else(|| *rs.clone());
+                return Some(Equal(Some(Box::new(a)), Some(Box::new(b))));
+            }
+            Negate(None) | Divide(None, _) | Power(None, _) => return Some(Value(0.0)),
+            Divide(Some(a), None) | Power(Some(a), None) => {
+                return Some(
+                    a.simplify_in(mode, cache.as_deref_mut())
+                        .unwrap_or_else(|| *a.clone()),
+                );
+            }
+            Equal(ls, rs) => {
+                let a = ls.clone().unwrap_or_else(|| Box::new(Value(0.0)));
+                let b = rs.clone().unwrap_or_else(|| Box::new(Value(0.0)));
+                let equal: Operation = Equal(Some(a), Some(b));
+                return Some(
+                    equal
+                        .simplify_in(mode, cache.as_deref_mut())
+                        .unwrap_or(equal),
+                );
+            }
+            Piecewise(branches, default) => {
+                let mut simplify = |x: &Operation| {
+                    x.simplify_in(mode, cache.as_deref_mut())
+                        .unwrap_or_else(|| x.clone())
+                };
+                let mut result: Vec<(Operation, Operation)> = Vec::new();
+                let mut default: Option<Box<Operation>> =
+                    default.as_ref().map(|x| Box::new(simplify(x)));
+                for (condition, value) in branches {
+                    let condition: Operation = simplify(condition);
+                    let constant: bool = match &condition {
+                        Value(_) => true,
+                        Equal(Some(a), Some(b)) => {
+                            matches!((a.as_ref(), b.as_ref()), (Value(_), Value(_)))
+                        }
+                        _ => false,
+                    };
+                    if !constant {
+                        result.push((condition, simplify(value)));
+                    } else if condition_holds(&condition) {
+                        // A constant true condition makes the later branches unreachable
+                        default = Some(Box::new(simplify(value)));
+                        break;
+                    }
+                    // A constant false condition can never be taken
+                }
+                if result.is_empty() {
+                    if let Some(default) = default {
+                        return Some(*default);
+                    }
+                }
+                return Some(Piecewise(result, default));
+            }
+            Log(Some(a)) => {
+                let simplification: Option<Operation> = a.simplify_in(mode, cache);
+                match simplification.as_ref().unwrap_or(a) {
+                    // ln(1) = 0
+                    Value(b) if *b == 1.0 => return Some(Value(0.0)),
+                    // Other logarithms are irrational, so they are only folded numerically
+                    Value(b) if *b > 0.0 && b.is_finite() && mode == SimplifyMode::Numeric => {
+                        return Some(Value(b.ln()));
+                    }
+                    _ => {}
+                }
+                return simplification
+                    .filter(|x| x != a.as_ref())
+                    .map(|x| Log(Some(Box::new(x))));
+            }
+            Value(_) => return Some(self.clone()),
+            Rational(n, d) if reduce_rational(*n, *d) != Some((*n, *d)) => {
+                return rational_or_value(*n, *d)
+            }
+            _ => {}
+        }
+
+        None
     }
-}
 
-impl Add for Operation {
-    type Output = Operation;
+    /// Returns the binding strength of the operation when written inline.
+    ///
+    /// Higher values bind tighter: `Equal` is lowest, followed by `Sum`, then `Multiply`,
+    /// `Divide` and `Rational`, then `Negate`, then `Power`, with atoms highest. A child with
+    /// a lower precedence than its parent needs to be parenthesized.
+    pub fn precedence(&self) -> u8 {
+        match self {
+            Equal(_, _) => 0,
+            Sum(_) => 1,
+            Multiply(_) | Divide(_, _) | Rational(_, _) => 2,
+            Negate(_) => 3,
+            Power(_, _) => 4,
+            Value(_) | Mapping(_) | Text(_) | Variable(_) | Display(_) | Piecewise(_, _) => 5,
+            // Written as a function call, ln(x)
+            Log(_) => 5,
+        }
+    }
 
-    fn add(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Sum(mut a), Sum(mut b)) => {
-                a.append(&mut b);
-                Sum(a)
+    pub fn print_operation_type(&self) -> &'static str {
+        match self {
+            Multiply(_) => "Multiply",
+            Negate(_) => "Negate",
+            Log(_) => "Log",
+            Sum(_) => "Sum",
+            Divide(_, _) => "Divide",
+            Equal(_, _) => "Equal",
+            Value(_) => "Value",
+            Mapping(_) => "Mapping",
+            Text(_) => "Text",
+            Variable(_) => "Variable",
+            Display(_) => "Display",
+            Power(_, _) => "Power",
+            Rational(_, _) => "Rational",
+            Piecewise(_, _) => "Piecewise",
+        }
+    }
+
+    /// Checks if the two trees have the same shape, as used by the mapping tables.
+    ///
+    /// A `Mapping` on either side is a wildcard that matches any subtree, negations are
+    /// looked through and leaves match each other regardless of their value.
+    pub fn compare_structure(&self, rs: &Operation) -> bool {
+        match (self, rs) {
+            (Sum(ls), Sum(rs)) | (Multiply(ls), Multiply(rs)) => {
+                if ls.len() != rs.len() {
+                    return false;
+                }
+                for (l, r) in ls.iter().zip(rs.iter()) {
+                    if !l.compare_structure(r) {
+                        return false;
+                    }
+                }
+                true
             }
-            (Sum(mut a), b) => {
-                a.push(b);
-                Sum(a)
+            (Negate(Some(ls)), Negate(Some(rs))) | (Log(Some(ls)), Log(Some(rs))) => {
+                ls.compare_structure(rs)
             }
-            (a, Sum(mut b)) => {
-                b.insert(0, a);
-                Sum(b)
+            (Negate(Some(ls)), _) => ls.compare_structure(rs),
+            (_, Negate(Some(rs))) => rs.compare_structure(self),
+            (Divide(Some(lsn), Some(lsd)), Divide(Some(rsn), Some(rsd)))
+            | (Power(Some(lsn), Some(lsd)), Power(Some(rsn), Some(rsd))) => {
+                let denominator: bool = lsd.compare_structure(rsd);
+                let numerator_match: bool = lsn.compare_structure(rsn);
+                denominator && numerator_match
             }
-            (Value(a), Value(b)) => Value(a.value() + b.value()),
-            (a, b) => Sum(vec![a, b]),
+            (_, Mapping(_)) | (Mapping(_), _) => true,
+            (a, b) => a.matches(b),
         }
     }
-}
 
-impl Index<usize> for Operation {
-    type Output = Operation;
-
-    fn index(&self, index: usize) -> &Self::Output {
+    /// Removes redundant structure from the tree without evaluating anything.
+    ///
+    /// Double negations are removed, `Value(0.0)` terms are dropped from sums and `Value(1.0)`
+    /// factors from products, and single element sums and products are replaced by the element.
+    pub fn cleanup(&mut self) {
+        self.children_mut().into_iter().for_each(|x| x.cleanup());
         match self {
-            Multiply(a) => a.get(index).unwrap(),
-            Sum(a) => a.get(index).unwrap(),
-            _ => panic!("Cannot Index This Operation"),
+            Negate(Some(a)) => {
+                if let Negate(Some(b)) = a.as_ref() {
+                    *self = *b.clone();
+                }
+            }
+            Sum(list) => {
+                list.retain(|x| !matches!(x, Value(a) if *a == 0.0));
+                match list.len() {
+                    0 => *self = Value(0.0),
+                    1 => *self = list.remove(0),
+                    _ => {}
+                }
+            }
+            Multiply(list) => {
+                list.retain(|x| !matches!(x, Value(a) if *a == 1.0));
+                match list.len() {
+                    0 => *self = Value(1.0),
+                    1 => *self = list.remove(0),
+                    _ => {}
+                }
+            }
+            _ => {}
         }
     }
-}
 
-impl num_traits::Zero for Operation {
-    fn zero() -> Self {
-        Value(0.0)
+    /// Evaluates the operation with the symbols named in `bindings` set to their values.
+    ///
+    /// Returns an error if a symbol is neither bound nor a `Variable` with a finite value.
+    pub fn evaluate(&self, bindings: &HashMap<String, f64>) -> Result<f64, String> {
+        let names: Vec<String> = bindings.keys().cloned().collect();
+        let values: Vec<f64> = names.iter().map(|x| bindings[x]).collect();
+        Ok(self.compile(&names)?(&values))
     }
 
-    fn is_zero(&self) -> bool {
+    /// Formats the value of a numeric operation with `digits` decimals, e.g. `22 / 7` as
+    /// `3.143` for three digits.
+    ///
+    /// Returns `None` for an `Equal`, for a non-finite value, or if a symbol is not a
+    /// `Variable` with a finite value.
+    pub fn to_decimal_string(&self, digits: usize) -> Option<String> {
+        if let Equal(_, _) = self {
+            return None;
+        }
+        let value: f64 = self.evaluate(&HashMap::new()).ok()?;
+        value.is_finite().then(|| format!("{:.*}", digits, value))
+    }
+
+    /// Evaluates the operation in the complex field with the symbols named in `bindings` set
+    /// to their values.
+    ///
+    /// Unbound symbols named `j` or `i` are the imaginary unit. Other unbound symbols must be
+    /// a `Variable` with a finite value, as in `evaluate`. An `Equal` evaluates to the
+    /// difference of its sides.
+    pub fn evaluate_complex(
+        &self,
+        bindings: &HashMap<String, Complex<f64>>,
+    ) -> Result<Complex<f64>, String> {
+        let evaluate = |x: &Operation| x.evaluate_complex(bindings);
         match self {
-            Variable(a) => a.is_zero(),
-            Value(a) => *a == 0.0,
-            _ => false,
+            Value(_) | Rational(_, _) => Ok(Complex::new(self.value(), 0.0)),
+            Text(_) | Variable(_) => {
+                let name: String = self.equation_repr();
+                if let Some(a) = bindings.get(&name) {
+                    return Ok(*a);
+                }
+                match self.get_child() {
+                    Some(a) if a.value().is_finite() => Ok(Complex::new(a.value(), 0.0)),
+                    _ if name == "j" || name == "i" => Ok(Complex::i()),
+                    _ => Err(format!("Unbound variable {}", name)),
+                }
+            }
+            Sum(list) => list.iter().map(evaluate).sum(),
+            Multiply(list) => list.iter().map(evaluate).product(),
+            Negate(Some(a)) => Ok(-evaluate(a)?),
+            Log(Some(a)) => Ok(evaluate(a)?.ln()),
+            Divide(Some(a), Some(b)) => Ok(evaluate(a)? / evaluate(b)?),
+            Power(Some(a), Some(b)) => {
+                let (a, b) = (evaluate(a)?, evaluate(b)?);
+                if b.im == 0.0 && b.re.fract() == 0.0 && b.re.abs() <= i32::MAX as f64 {
+                    return Ok(a.powi(b.re as i32));
+                }
+                Ok(a.powc(b))
+            }
+            Equal(Some(a), Some(b)) => Ok(evaluate(a)? - evaluate(b)?),
+            _ => Err(format!(
+                "Cannot evaluate {} operation",
+                self.print_operation_type()
+            )),
+        }
+    }
+
+    /// Compiles the operation into a closure for fast repeated evaluation.
+    ///
+    /// The closure takes the values of `vars` in order. Symbols are resolved once here,
+    /// symbols not listed in `vars` must be a `Variable` with a finite value. An `Equal`
+    /// evaluates to the difference of its sides, like `math::Equation::value`.
+    ///
+    /// The slice passed to the closure should be as long as `vars`, symbols whose value is
+    /// missing from a shorter slice are NaN.
+    pub fn compile(&self, vars: &[String]) -> Result<Compiled, String> {
+        let compile_all = |list: &[Operation]| -> Result<Vec<Compiled>, String> {
+            list.iter().map(|x| x.compile(vars)).collect()
+        };
+        match self {
+            Value(a) => {
+                let a: f64 = *a;
+                Ok(Box::new(move |_| a))
+            }
+            Rational(_, _) => {
+                let a: f64 = self.value();
+                Ok(Box::new(move |_| a))
+            }
+            Text(_) | Variable(_) => {
+                let name: String = self.equation_repr();
+                if let Some(index) = vars.iter().position(|x| *x == name) {
+                    return Ok(Box::new(move |values: &[f64]| {
+                        values.get(index).copied().unwrap_or(f64::NAN)
+                    }));
+                }
+                match self.get_child() {
+                    Some(a) if a.value().is_finite() => {
+                        let a: f64 = a.value();
+                        Ok(Box::new(move |_| a))
+                    }
+                    _ => Err(format!("Unbound variable {}", name)),
+                }
+            }
+            Sum(list) => {
+                let list: Vec<Compiled> = compile_all(list)?;
+                Ok(Box::new(move |values: &[f64]| {
+                    list.iter().map(|x| x(values)).sum()
+                }))
+            }
+            Multiply(list) => {
+                let list: Vec<Compiled> = compile_all(list)?;
+                Ok(Box::new(move |values: &[f64]| {
+                    list.iter().map(|x| x(values)).product()
+                }))
+            }
+            Negate(Some(a)) => {
+                let a: Compiled = a.compile(vars)?;
+                Ok(Box::new(move |values: &[f64]| -a(values)))
+            }
+            Log(Some(a)) => {
+                let a: Compiled = a.compile(vars)?;
+                Ok(Box::new(move |values: &[f64]| a(values).ln()))
+            }
+            Divide(Some(a), Some(b)) => {
+                let (a, b) = (a.compile(vars)?, b.compile(vars)?);
+                Ok(Box::new(move |values: &[f64]| a(values) / b(values)))
+            }
+            Power(Some(a), Some(b)) => {
+                let (a, b) = (a.compile(vars)?, b.compile(vars)?);
+                Ok(Box::new(move |values: &[f64]| a(values).powf(b(values))))
+            }
+            Equal(Some(a), Some(b)) => {
+                let (a, b) = (a.compile(vars)?, b.compile(vars)?);
+                Ok(Box::new(move |values: &[f64]| a(values) - b(values)))
+            }
+            Piecewise(branches, default) => {
+                // An Equal condition compiles to one when its sides are equal, zero otherwise
+                let condition = |c: &Operation| -> Result<Compiled, String> {
+                    match c {
+                        Equal(Some(a), Some(b)) => {
+                            let (a, b) = (a.compile(vars)?, b.compile(vars)?);
+                            Ok(Box::new(
+                                move |values: &[f64]| {
+                                    if a(values) == b(values) {
+                                        1.0
+                                    } else {
+                                        0.0
+                                    }
+                                },
+                            ))
+                        }
+                        _ => c.compile(vars),
+                    }
+                };
+                let branches: Vec<(Compiled, Compiled)> = branches
+                    .iter()
+                    .map(|(c, v)| Ok((condition(c)?, v.compile(vars)?)))
+                    .collect::<Result<Vec<(Compiled, Compiled)>, String>>()?;
+                let default: Option<Compiled> = match default {
+                    Some(a) => Some(a.compile(vars)?),
+                    None => None,
+                };
+                Ok(Box::new(move |values: &[f64]| {
+                    for (condition, value) in &branches {
+                        let condition: f64 = condition(values);
+                        if condition != 0.0 && !condition.is_nan() {
+                            return value(values);
+                        }
+                    }
+                    default.as_ref().map_or(f64::NAN, |x| x(values))
+                }))
+            }
+            _ => Err(format!(
+                "Cannot compile {} operation",
+                self.print_operation_type()
+            )),
         }
     }
-}
 
-impl Default for Operation {
-    fn default() -> Self {
-        Value(0.0)
+    /// Evaluates the operation at `steps` evenly spaced values of `var` from `start` to
+    /// `end` inclusive, returning `(x, y)` pairs.
+    ///
+    /// The operation is compiled once. Points outside its domain yield NaN, as do all
+    /// points if another symbol has no value.
+    pub fn evaluate_grid(&self, var: &str, start: f64, end: f64, steps: usize) -> Vec<(f64, f64)> {
+        let compiled: Option<Compiled> = self.compile(&[var.to_string()]).ok();
+        (0..steps)
+            .map(|i| {
+                let x: f64 = match steps {
+                    1 => start,
+                    _ => start + (end - start) * i as f64 / (steps - 1) as f64,
+                };
+                (x, compiled.as_ref().map_or(f64::NAN, |f| f(&[x])))
+            })
+            .collect()
+    }
+
+    /// Returns each intermediate state of simplifying the operation, one rewrite at a time.
+    ///
+    /// The first entry is the operation itself and the last is the fixed point. Every
+    /// step simplifies a single subtree, preferring the deepest ones first, so consecutive
+    /// entries differ by exactly one rewrite.
+    pub fn simplify_steps(&self) -> Vec<Operation> {
+        let mut steps: Vec<Operation> = vec![self.clone()];
+        while steps.len() < MAX_SIMPLIFY_STEPS {
+            match steps.last().unwrap().simplify_step() {
+                Some(next) => steps.push(next),
+                None => break,
+            }
+        }
+        steps
+    }
+
+    /// Applies a single simplification to the deepest subtree that can be simplified.
+    ///
+    /// Returns `None` once no subtree changes when simplified.
+    fn simplify_step(&self) -> Option<Operation> {
+        let mut output: Operation = self.clone();
+        for child in output.children_mut() {
+            if let Some(step) = child.simplify_step() {
+                *child = step;
+                return Some(output);
+            }
+        }
+        match self.simplify() {
+            Some(result) if result.latex_string() != self.latex_string() => Some(result),
+            _ => None,
+        }
+    }
+
+    /// Moves every term of an `Equal` to the left side, returning `left - right = 0` with the
+    /// left side simplified. Returns `None` if the operation is not a complete `Equal`.
+    pub fn normalize_equal(&self) -> Option<Operation> {
+        match self {
+            Equal(Some(a), Some(b)) => {
+                let difference: Operation = Sum(vec![*a.clone(), Negate(Some(b.clone()))]);
+                Some(Equal(
+                    Some(Box::new(difference.simplify_fully())),
+                    Some(Box::new(Value(0.0))),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns true if `simplify` would leave the operation unchanged.
+    ///
+    /// Obviously reducible trees, with several constants in one sum or product, nested sums
+    /// or products, `x * 1`, `x + 0` or double negations, are rejected without simplifying.
+    pub fn is_simplified(&self) -> bool {
+        let mut reducible: bool = false;
+        self.walk(&mut |x| {
+            reducible |= match x {
+                Sum(list) => {
+                    list.iter().filter(|y| matches!(y, Value(_))).count() > 1
+                        || list.iter().any(|y| matches!(y, Sum(_) | Value(0.0)))
+                }
+                Multiply(list) => {
+                    list.iter().filter(|y| matches!(y, Value(_))).count() > 1
+                        || list.iter().any(|y| matches!(y, Multiply(_) | Value(1.0)))
+                }
+                Negate(Some(a)) => matches!(a.as_ref(), Negate(_) | Value(_)),
+                _ => false,
+            }
+        });
+        if reducible {
+            return false;
+        }
+        match self.simplify() {
+            Some(result) => result == *self,
+            None => true,
+        }
+    }
+
+    /// Simplifies the operation treating any `Value` smaller than `epsilon` in magnitude as zero.
+    ///
+    /// Values are snapped before simplifying, so they can be dropped from sums, and again
+    /// afterwards to clean up residuals produced by folding.
+    pub fn simplify_approx(&self, epsilon: f64) -> Operation {
+        let mut output: Operation = self.clone();
+        output.snap_values(epsilon);
+        let mut output: Operation = output.simplify().unwrap_or(output);
+        output.snap_values(epsilon);
+        output
+    }
+
+    /// Simplifies the operation with the rewrites enabled in `options` applied first.
+    ///
+    /// With the default options this is the same as `simplify`, returning the operation
+    /// unchanged if it cannot be simplified.
+    pub fn simplify_with_options(&self, options: SimplifyOptions) -> Operation {
+        let mut output: Operation = self.clone();
+        if options.divide_to_reciprocal {
+            output.divide_to_reciprocal();
+        }
+        output.simplify_with(options.mode).unwrap_or(output)
+    }
+
+    /// Simplifies the operation using rules that are only valid under `assumptions`.
+    ///
+    /// `x / x` cancels to one when `x` is known to be nonzero, and `(x^a)^b` combines to
+    /// `x^(a * b)`, so `sqrt(x^2)` becomes `x`, when `x` is known to be positive. The result
+    /// is then simplified as usual.
+    pub fn simplify_with_assumptions(&self, assumptions: &Assumptions) -> Operation {
+        let mut output: Operation = self.clone();
+        output.apply_assumptions(assumptions);
+        output.simplify().unwrap_or(output)
+    }
+
+    /// Applies the rewrites of `simplify_with_assumptions` bottom up.
+    fn apply_assumptions(&mut self, assumptions: &Assumptions) {
+        self.children_mut()
+            .into_iter()
+            .for_each(|x| x.apply_assumptions(assumptions));
+        match self {
+            Divide(Some(a), Some(b)) if same_base(a, b) && assumptions.is_nonzero(b) => {
+                *self = Value(1.0);
+            }
+            Power(Some(base), Some(exponent)) => {
+                if let Power(Some(inner_base), Some(inner_exponent)) = base.as_ref() {
+                    if assumptions.is_positive(inner_base) {
+                        let exponent: Operation =
+                            multiply_exponents(*inner_exponent.clone(), *exponent.clone());
+                        *self = Power(Some(inner_base.clone()), Some(Box::new(exponent)));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Rewrites every `expr / c` with a nonzero `Value` divisor into `(1 / c) * expr`.
+    ///
+    /// The factors of a `Multiply` numerator join the new product directly, the rest of the
+    /// tree keeps its nesting.
+    fn divide_to_reciprocal(&mut self) {
+        self.children_mut()
+            .into_iter()
+            .for_each(|x| x.divide_to_reciprocal());
+        if let Divide(Some(a), Some(b)) = self {
+            if let Value(c) = b.as_ref() {
+                if *c != 0.0 {
+                    let mut list: Vec<Operation> = vec![Value(1.0 / c)];
+                    match a.as_ref() {
+                        Multiply(factors) => list.extend(factors.iter().cloned()),
+                        _ => list.push(*a.clone()),
+                    }
+                    *self = Multiply(list);
+                }
+            }
+        }
+    }
+
+    /// Replaces every `Value` smaller than `epsilon` in magnitude with `Value(0.0)`.
+    fn snap_values(&mut self, epsilon: f64) {
+        if let Value(a) = self {
+            if a.abs() < epsilon {
+                *a = 0.0;
+            }
+        }
+        self.children_mut()
+            .into_iter()
+            .for_each(|x| x.snap_values(epsilon));
+    }
+
+    /// Checks if the tree still contains `Mapping` placeholders.
+    ///
+    /// Templates have no value, `value` panics on a `Mapping` and `evaluate` returns an
+    /// error, so fill them in with `fill_mappings` first.
+    pub fn is_template(&self) -> bool {
+        matches!(self, Mapping(_)) || self.children().into_iter().any(|x| x.is_template())
+    }
+
+    /// Replaces every `Mapping(i)` in the tree with `values[i]`.
+    ///
+    /// Mappings without a corresponding value are left untouched so a template can be
+    /// filled in over several passes.
+    pub fn fill_mappings(&self, values: &[Operation]) -> Operation {
+        if let Mapping(index) = self {
+            if let Some(value) = values.get(*index) {
+                return value.clone();
+            }
+        }
+        let mut output: Operation = self.clone();
+        for child in output.children_mut() {
+            *child = child.fill_mappings(values);
+        }
+        output
+    }
+
+    /// Expands integer powers of sums into polynomials, e.g. `(x + 1)^2` into `x^2 + 2 * x + 1`.
+    ///
+    /// Only `Power(Sum, Value(n))` nodes with an integer `2 <= n <= max_exponent` are expanded,
+    /// the cap keeps the number of produced terms from blowing up. Like terms are collected in
+    /// the order they are first produced.
+    pub fn expand_powers(&self, max_exponent: u32) -> Operation {
+        let mut output: Operation = self.clone();
+        for child in output.children_mut() {
+            *child = child.expand_powers(max_exponent);
+        }
+        if let Power(Some(base), Some(exponent)) = &output {
+            if let (Sum(list), Value(n)) = (base.as_ref(), exponent.as_ref()) {
+                if n.fract() == 0.0 && *n >= 2.0 && *n <= max_exponent as f64 {
+                    let terms: Vec<Monomial> = list.iter().map(monomial_of).collect();
+                    let mut result: Vec<Monomial> = terms.clone();
+                    for _ in 1..(*n as u32) {
+                        result = multiply_polynomials(&result, &terms);
+                    }
+                    return Sum(result.into_iter().map(monomial_to_operation).collect());
+                }
+            }
+        }
+        output
+    }
+
+    /// Returns the subtree at `path`, a list of child indices starting from this operation.
+    ///
+    /// Indices select the terms of a `Sum` or `Multiply`, `0` the operand of a `Negate` and
+    /// `0`/`1` the operands of a `Divide`, `Equal` or `Power`. An empty path is `self`.
+    pub fn path_get(&self, path: &[usize]) -> Option<&Operation> {
+        match path.split_first() {
+            Some((index, rest)) => self.children().get(*index)?.path_get(rest),
+            None => Some(self),
+        }
+    }
+
+    /// Replaces the subtree at `path` with `new`, see `path_get` for the path format.
+    ///
+    /// Returns false and leaves the operation untouched if the path does not exist.
+    pub fn path_set(&mut self, path: &[usize], new: Operation) -> bool {
+        match path.split_first() {
+            Some((index, rest)) => match self.children_mut().into_iter().nth(*index) {
+                Some(child) => child.path_set(rest, new),
+                None => false,
+            },
+            None => {
+                *self = new;
+                true
+            }
+        }
+    }
+
+    /// Pulls the factors shared by every term of a `Sum` out in front of it.
+    ///
+    /// Both the greatest common divisor of integer coefficients and symbolic factors are
+    /// extracted, so `6 * x^2 + 4 * x` becomes `2 * x * (3 * x + 2)`. Nested sums are factored
+    /// first, sums without a common factor are returned unchanged.
+    pub fn factor(&self) -> Operation {
+        let mut output: Operation = self.clone();
+        for child in output.children_mut() {
+            *child = child.factor();
+        }
+        let terms: Vec<Monomial> = match &output {
+            Sum(list) if list.len() > 1 => list.iter().map(monomial_of).collect(),
+            _ => return output,
+        };
+
+        let divisor: f64 = terms
+            .iter()
+            .map(|(coefficient, _)| *coefficient)
+            .try_fold(0, |a, b| {
+                (b.fract() == 0.0 && b.abs() < i64::MAX as f64)
+                    .then(|| gcd(a, (b as i64).unsigned_abs()))
+            })
+            .filter(|x| *x > 1)
+            .map_or(1.0, |x| x as f64);
+        let mut common: Vec<(Operation, f64)> = Vec::new();
+        for (base, exponent) in &terms[0].1 {
+            let shared: Option<f64> = terms[1..].iter().try_fold(*exponent, |a, (_, factors)| {
+                let (_, b) = factors.iter().find(|(x, _)| same_base(x, base))?;
+                Some(a.min(*b))
+            });
+            if let Some(exponent) = shared.filter(|x| *x > 0.0) {
+                common.push((base.clone(), exponent));
+            }
+        }
+        if divisor == 1.0 && common.is_empty() {
+            return output;
+        }
+
+        let remaining: Vec<Operation> = terms
+            .iter()
+            .map(|term| {
+                let inverse: Monomial = (
+                    1.0 / divisor,
+                    common.iter().map(|(x, e)| (x.clone(), -e)).collect(),
+                );
+                monomial_to_operation(multiply_monomials(term, &inverse))
+            })
+            .collect();
+        let mut list: Vec<Operation> = match monomial_to_operation((divisor, common)) {
+            Multiply(list) => list,
+            x => vec![x],
+        };
+        list.push(Sum(remaining));
+        Multiply(list)
+    }
+
+    /// Serializes the operation as an S-expression, e.g. `(/ (+ x y) z)`.
+    ///
+    /// Operators are `+`, `*`, `-` (negation), `/`, `^` and `=`, mappings are written as
+    /// `(map i)`, exact fractions as `(rational n d)` and missing operands as `nil`. Symbols
+    /// that would be ambiguous are double quoted, with `"` and `\` inside them escaped by a
+    /// backslash. `Variable` and `Display` members are written by name and read back as
+    /// `Text`.
+    pub fn to_sexpr(&self) -> String {
+        let child = |x: &Option<Box<Operation>>| match x {
+            Some(x) => x.to_sexpr(),
+            None => "nil".to_string(),
+        };
+        let list = |head: &str, list: &[Operation]| {
+            let mut items: Vec<String> = vec![head.to_string()];
+            items.extend(list.iter().map(|x| x.to_sexpr()));
+            format!("({})", items.join(" "))
+        };
+        match self {
+            Sum(items) => list("+", items),
+            Multiply(items) => list("*", items),
+            Negate(a) => format!("(- {})", child(a)),
+            Log(a) => format!("(ln {})", child(a)),
+            Divide(a, b) => format!("(/ {} {})", child(a), child(b)),
+            Power(a, b) => format!("(^ {} {})", child(a), child(b)),
+            Equal(a, b) => format!("(= {} {})", child(a), child(b)),
+            Value(a) => a.to_string(),
+            Mapping(a) => format!("(map {})", a),
+            Rational(n, d) => format!("(rational {} {})", n, d),
+            Piecewise(branches, default) => {
+                let mut items: Vec<String> = vec!["piecewise".to_string()];
+                for (c, v) in branches {
+                    items.push(c.to_sexpr());
+                    items.push(v.to_sexpr());
+                }
+                items.push(child(default));
+                format!("({})", items.join(" "))
+            }
+            Text(_) | Variable(_) | Display(_) => {
+                let name: String = self.equation_repr();
+                let plain: bool = !name.is_empty()
+                    && name != "nil"
+                    && name.parse::<f64>().is_err()
+                    && !name.contains(|c: char| c.is_whitespace() || "()\"\\".contains(c));
+                if plain {
+                    name
+                } else {
+                    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+                }
+            }
+        }
+    }
+
+    /// Parses an S-expression produced by `to_sexpr`.
+    pub fn from_sexpr(input: &str) -> Result<Operation, String> {
+        let tokens: Vec<String> = tokenize_sexpr(input)?;
+        let mut position: usize = 0;
+        let output: Operation =
+            parse_sexpr(&tokens, &mut position)?.ok_or("Expected an operation, found nil")?;
+        if position != tokens.len() {
+            return Err(format!("Unexpected token {}", tokens[position]));
+        }
+        Ok(output)
+    }
+
+    /// Renders the tree as a Graphviz DOT graph.
+    ///
+    /// Every node gets its own id, so repeated subtrees are drawn separately. Leaves are
+    /// labeled by their `equation_repr`, other nodes by `print_operation_type`.
+    pub fn to_dot(&self) -> String {
+        let mut output: String = String::from("digraph {\n");
+        self.write_dot(&mut output, &mut 0);
+        output.push('}');
+        output
+    }
+
+    /// Writes this node and its children to `output`, returning the id of this node.
+    fn write_dot(&self, output: &mut String, next_id: &mut usize) -> usize {
+        let id: usize = *next_id;
+        *next_id += 1;
+        let children: Vec<&Operation> = self.children();
+        let label: String = if children.is_empty() {
+            self.equation_repr()
+        } else {
+            self.print_operation_type().to_string()
+        };
+        output.push_str(&format!(
+            "    n{} [label=\"{}\"];\n",
+            id,
+            label.replace('\\', "\\\\").replace('"', "\\\"")
+        ));
+        for child in children {
+            let child_id: usize = child.write_dot(output, next_id);
+            output.push_str(&format!("    n{} -> n{};\n", id, child_id));
+        }
+        id
+    }
+
+    /// Builds an `Equal` from the S-expressions of its two sides, see `from_sexpr`.
+    pub fn equation_from_strings(lhs: &str, rhs: &str) -> Result<Operation, String> {
+        Ok(Equal(
+            Some(Box::new(Operation::from_sexpr(lhs)?)),
+            Some(Box::new(Operation::from_sexpr(rhs)?)),
+        ))
+    }
+
+    /// Returns the symbolic derivative of the operation with respect to the symbol `var`.
+    ///
+    /// The result is not simplified. Returns `None` for operations that cannot be
+    /// differentiated symbolically, such as a power with `var` in both the base and the
+    /// exponent (which would need a logarithm), `Mapping` placeholders or `Display` members.
+    pub fn derivative(&self, var: &str) -> Option<Operation> {
+        let boxed = |x: Operation| Some(Box::new(x));
+        Some(match self {
+            Value(_) | Rational(_, _) => Value(0.0),
+            Text(_) | Variable(_) => Value(if self.equation_repr() == var {
+                1.0
+            } else {
+                0.0
+            }),
+            Sum(list) => Sum(list
+                .iter()
+                .map(|x| x.derivative(var))
+                .collect::<Option<Vec<Operation>>>()?),
+            Negate(Some(a)) => Negate(boxed(a.derivative(var)?)),
+            // d/dx ln(u) = u' / u
+            Log(Some(a)) => Divide(boxed(a.derivative(var)?), Some(a.clone())),
+            Multiply(list) => {
+                let mut terms: Vec<Operation> = Vec::new();
+                for (i, item) in list.iter().enumerate() {
+                    let mut factors: Vec<Operation> = list.clone();
+                    factors[i] = item.derivative(var)?;
+                    terms.push(Multiply(factors));
+                }
+                Sum(terms)
+            }
+            Divide(Some(a), Some(b)) => Divide(
+                boxed(Sum(vec![
+                    Multiply(vec![a.derivative(var)?, *b.clone()]),
+                    Negate(boxed(Multiply(vec![*a.clone(), b.derivative(var)?]))),
+                ])),
+                boxed(Power(Some(b.clone()), boxed(Value(2.0)))),
+            ),
+            Power(Some(a), Some(b)) if b.is_constant_in(var) => Multiply(vec![
+                *b.clone(),
+                Power(
+                    Some(a.clone()),
+                    boxed(subtract_exponents(*b.clone(), Value(1.0))),
+                ),
+                a.derivative(var)?,
+            ]),
+            // d/dx c^u = c^u * ln(c) * u'
+            Power(Some(a), Some(b)) => match a.as_ref() {
+                Value(c) if *c > 0.0 => {
+                    Multiply(vec![self.clone(), Value(c.ln()), b.derivative(var)?])
+                }
+                _ => return None,
+            },
+            Equal(Some(a), Some(b)) => Equal(boxed(a.derivative(var)?), boxed(b.derivative(var)?)),
+            // The derivative is taken branch by branch, ignoring the boundaries
+            Piecewise(branches, default) => Piecewise(
+                branches
+                    .iter()
+                    .map(|(c, v)| Some((c.clone(), v.derivative(var)?)))
+                    .collect::<Option<Vec<(Operation, Operation)>>>()?,
+                match default {
+                    Some(a) => boxed(a.derivative(var)?),
+                    None => None,
+                },
+            ),
+            _ => return None,
+        })
+    }
+
+    /// Returns an antiderivative of the operation with respect to the symbol `var`, without
+    /// the constant of integration.
+    ///
+    /// Only constants, numeric powers of `var`, sums and constant multiples are handled, the
+    /// result is not simplified. `1 / x` and `x^-1` integrate to `ln(x)`, which is only real
+    /// for positive `x`. Returns `None` for anything else, including products of several
+    /// factors depending on `var`.
+    pub fn integrate(&self, var: &str) -> Option<Operation> {
+        let boxed = |x: Operation| Some(Box::new(x));
+        if self.is_constant_in(var) {
+            return Some(Multiply(vec![self.clone(), Text(var.to_string())]));
+        }
+        Some(match self {
+            // x = x^1
+            Text(_) | Variable(_) => Divide(
+                boxed(Power(boxed(self.clone()), boxed(Value(2.0)))),
+                boxed(Value(2.0)),
+            ),
+            Power(Some(a), Some(b))
+                if a.equation_repr() == var && a.is_leaf() && b.is_constant_in(var) =>
+            {
+                let exponent: f64 = b.evaluate(&HashMap::new()).ok()?;
+                if exponent == -1.0 {
+                    return Some(Log(Some(a.clone())));
+                }
+                let exponent: Operation = add_exponents(*b.clone(), Value(1.0));
+                Divide(
+                    boxed(Power(Some(a.clone()), boxed(exponent.clone()))),
+                    boxed(exponent),
+                )
+            }
+            Sum(list) => Sum(list
+                .iter()
+                .map(|x| x.integrate(var))
+                .collect::<Option<Vec<Operation>>>()?),
+            Negate(Some(a)) => Negate(boxed(a.integrate(var)?)),
+            Multiply(list) => {
+                let dependent: Vec<usize> = (0..list.len())
+                    .filter(|i| !list[*i].is_constant_in(var))
+                    .collect();
+                let [i] = dependent[..] else {
+                    return None;
+                };
+                let mut factors: Vec<Operation> = list.clone();
+                factors[i] = list[i].integrate(var)?;
+                Multiply(factors)
+            }
+            // c / x = c * x^-1
+            Divide(Some(a), Some(b))
+                if a.is_constant_in(var) && b.equation_repr() == var && b.is_leaf() =>
+            {
+                Multiply(vec![*a.clone(), Log(Some(b.clone()))])
+            }
+            Divide(Some(a), Some(b)) if b.is_constant_in(var) => {
+                Divide(boxed(a.integrate(var)?), Some(b.clone()))
+            }
+            _ => return None,
+        })
+    }
+
+    /// Generates an arbitrary well formed tree at most `max_depth` operations deep.
+    ///
+    /// Leaves are the symbols `x`, `y` and `z` or small integer values, and exponents are
+    /// small non negative integers, so fully numeric trees have a finite value unless they
+    /// divide by zero.
+    #[cfg(feature = "test-util")]
+    pub fn random(rng: &mut impl rand::Rng, max_depth: usize) -> Operation {
+        let child = |rng: &mut _| Box::new(Operation::random(rng, max_depth - 1));
+        let leaf = max_depth == 0 || rng.gen_bool(0.25);
+        match if leaf {
+            rng.gen_range(0..2)
+        } else {
+            rng.gen_range(2..7)
+        } {
+            0 => Value(rng.gen_range(-5..=5) as f64),
+            1 => Text(["x", "y", "z"][rng.gen_range(0..3)].to_string()),
+            2 => Sum((0..rng.gen_range(2..4)).map(|_| *child(rng)).collect()),
+            3 => Multiply((0..rng.gen_range(2..4)).map(|_| *child(rng)).collect()),
+            4 => Negate(Some(child(rng))),
+            5 => Divide(Some(child(rng)), Some(child(rng))),
+            _ => Power(
+                Some(child(rng)),
+                Some(Box::new(Value(rng.gen_range(0..4) as f64))),
+            ),
+        }
+    }
+
+    /// Returns the distinct denominators of every `Divide` in the tree, in the order they
+    /// are first encountered. Duplicates are detected by their `latex_string`.
+    pub fn denominators(&self) -> Vec<Operation> {
+        let mut output: Vec<Operation> = Vec::new();
+        self.walk(&mut |x| {
+            if let Divide(_, Some(b)) = x {
+                if !output.iter().any(|y| y.latex_string() == b.latex_string()) {
+                    output.push(*b.clone());
+                }
+            }
+        });
+        output
+    }
+
+    /// Checks if two operations are the same up to a consistent renaming of their symbols.
+    ///
+    /// The `Text` and `Variable` names of the two trees must map one to one, so `a + b`
+    /// matches `x + y`, while `a + a` does not. Every other leaf must be equal.
+    pub fn alpha_eq(&self, other: &Operation) -> bool {
+        let mut forward: HashMap<String, String> = HashMap::new();
+        let mut backward: HashMap<String, String> = HashMap::new();
+        alpha_eq_with(self, other, &mut forward, &mut backward)
+    }
+
+    /// Returns the number of nodes in the tree, counting the operation itself.
+    pub fn node_count(&self) -> usize {
+        let mut count: usize = 0;
+        self.walk(&mut |_| count += 1);
+        count
+    }
+
+    /// Replaces every symbol defined in `context` with its definition.
+    ///
+    /// Definitions are expanded recursively. A symbol met again while its own definition is
+    /// being expanded is a cycle and is left as is, so with `a = b + 1` and `b = 2 * a` the
+    /// symbol `a` expands to `2 * a + 1`.
+    pub fn expand_definitions(&self, context: &Context) -> Operation {
+        self.expand_definitions_with(context, &mut Vec::new())
+    }
+
+    fn expand_definitions_with(&self, context: &Context, expanding: &mut Vec<String>) -> Operation {
+        if let Text(_) | Variable(_) = self {
+            let name: String = self.equation_repr();
+            return match context.get(&name) {
+                Some(definition) if !expanding.contains(&name) => {
+                    expanding.push(name);
+                    let output: Operation = definition.expand_definitions_with(context, expanding);
+                    expanding.pop();
+                    output
+                }
+                _ => self.clone(),
+            };
+        }
+        let mut output: Operation = self.clone();
+        output
+            .children_mut()
+            .into_iter()
+            .for_each(|x| *x = x.expand_definitions_with(context, expanding));
+        output
+    }
+
+    /// Returns the number of arithmetic operations needed to evaluate the tree once.
+    ///
+    /// A sum of n terms is n - 1 additions and a product of n factors is n - 1
+    /// multiplications. Divisions, negations, powers, rationals and the subtraction of the
+    /// sides of an `Equal` count as one each. Every branch of a `Piecewise` is counted, so
+    /// its count is an upper bound.
+    pub fn flop_count(&self) -> usize {
+        let mut count: usize = 0;
+        self.walk(&mut |x| {
+            count += match x {
+                Sum(list) | Multiply(list) => list.len().saturating_sub(1),
+                Divide(_, _) | Negate(_) | Power(_, _) | Rational(_, _) | Equal(_, _) => 1,
+                Log(_) => 1,
+                _ => 0,
+            }
+        });
+        count
+    }
+
+    /// Distributes negations over sums and merges the result into the enclosing sum, so
+    /// `a - (b - c)` becomes `a - b + c`. Double negations are removed, nothing is folded.
+    pub fn distribute_negations(&self) -> Operation {
+        let mut output: Operation = self.clone();
+        output
+            .children_mut()
+            .into_iter()
+            .for_each(|x| *x = x.distribute_negations());
+        let negate = |x: Operation| match x {
+            Negate(Some(a)) => *a,
+            x => Negate(Some(Box::new(x))),
+        };
+        match output {
+            Negate(Some(a)) => match *a {
+                Sum(list) => Sum(list.into_iter().map(negate).collect()),
+                a => negate(a),
+            },
+            Sum(list) => Sum(list
+                .into_iter()
+                .flat_map(|x| match x {
+                    Sum(inner) => inner,
+                    x => vec![x],
+                })
+                .collect()),
+            output => output,
+        }
+    }
+
+    /// Moves negations out of products and quotients and removes double negations, so every
+    /// term carries at most one `Negate` at its root, e.g. `(-a) * (-b) * -c` becomes
+    /// `-(a * b * c)`. Negations under a `Power` base or inside a sum are kept in place.
+    pub fn canonicalize_signs(&self) -> Operation {
+        let mut output: Operation = self.clone();
+        output
+            .children_mut()
+            .into_iter()
+            .for_each(|x| *x = x.canonicalize_signs());
+        let mut negated: bool = false;
+        let mut strip = |x: &mut Operation| {
+            if let Negate(Some(a)) = x {
+                *x = *a.clone();
+                negated = !negated;
+            }
+        };
+        match &mut output {
+            Negate(Some(a)) => {
+                strip(a);
+                negated = !negated;
+                output = *a.clone();
+            }
+            Multiply(list) => list.iter_mut().for_each(strip),
+            Divide(Some(a), Some(b)) => {
+                strip(a);
+                strip(b);
+            }
+            _ => {}
+        }
+        match negated {
+            true => Negate(Some(Box::new(output))),
+            false => output,
+        }
+    }
+
+    /// Matches the operation against a template, returning the subtree captured by each
+    /// `Mapping(i)` of `pattern` at index `i`.
+    ///
+    /// A `Mapping` matches any subtree, and every occurrence of the same index must match the
+    /// same subtree. The rest of the pattern must match exactly. Returns `None` if the
+    /// operation does not match, or if an index below the largest one is not used.
+    pub fn match_pattern(&self, pattern: &Operation) -> Option<Vec<Operation>> {
+        let mut captures: Vec<Option<Operation>> = Vec::new();
+        if !capture(pattern, self, &mut captures) {
+            return None;
+        }
+        captures.into_iter().collect()
+    }
+
+    /// Applies `rules` everywhere in the tree, bottom up.
+    ///
+    /// Children are rewritten before their parent, and each node is rewritten by the first
+    /// matching rule at most once, so rules whose template matches their own pattern do not
+    /// loop.
+    pub fn rewrite(&self, rules: &[RewriteRule]) -> Operation {
+        let mut output: Operation = self.clone();
+        output
+            .children_mut()
+            .into_iter()
+            .for_each(|x| *x = x.rewrite(rules));
+        rules
+            .iter()
+            .find_map(|x| x.apply(&output))
+            .unwrap_or(output)
+    }
+
+    /// Merges nested sums into their parent sum and nested products into their parent
+    /// product, so `(a + b) + (c + d)` becomes `a + b + c + d`.
+    pub fn flatten_associative(&self) -> Operation {
+        let mut output: Operation = self.clone();
+        output
+            .children_mut()
+            .into_iter()
+            .for_each(|x| *x = x.flatten_associative());
+        match output {
+            Sum(list) => Sum(list
+                .into_iter()
+                .flat_map(|x| match x {
+                    Sum(inner) => inner,
+                    x => vec![x],
+                })
+                .collect()),
+            Multiply(list) => Multiply(
+                list.into_iter()
+                    .flat_map(|x| match x {
+                        Multiply(inner) => inner,
+                        x => vec![x],
+                    })
+                    .collect(),
+            ),
+            output => output,
+        }
+    }
+
+    /// Splits a term into its numeric coefficient and symbolic base.
+    ///
+    /// The coefficient is the product of the `Value` factors, the base is the product of
+    /// the remaining factors, or `Value(1.0)` for a pure constant. Negations are folded into
+    /// the coefficient, so `-(2 * x)` decomposes to `(-2.0, x)`.
+    pub fn decompose_term(&self) -> (f64, Operation) {
+        match self {
+            Value(a) => (*a, Value(1.0)),
+            Rational(_, _) => (self.value(), Value(1.0)),
+            Negate(Some(a)) => {
+                let (coefficient, base) = a.decompose_term();
+                (-coefficient, base)
+            }
+            Multiply(list) => {
+                let mut coefficient: f64 = 1.0;
+                let mut factors: Vec<Operation> = Vec::new();
+                for item in list {
+                    let (c, base) = item.decompose_term();
+                    coefficient *= c;
+                    match base {
+                        Value(1.0) => {}
+                        Multiply(inner) => factors.extend(inner),
+                        base => factors.push(base),
+                    }
+                }
+                let base: Operation = match factors.len() {
+                    0 => Value(1.0),
+                    1 => factors.remove(0),
+                    _ => Multiply(factors),
+                };
+                (coefficient, base)
+            }
+            _ => (1.0, self.clone()),
+        }
+    }
+
+    /// Rewrites the operation as a fraction over `denominator` without reducing it, e.g.
+    /// `x / 2` over `6` as `(3 * x) / 6`.
+    ///
+    /// Numeric denominators must be an integer multiple of the current denominator.
+    /// Symbolic denominators must equal the current one, or be a product containing it.
+    /// Returns `None` otherwise. Terms without a fraction have the denominator `1`.
+    pub fn over_denominator(&self, denominator: &Operation) -> Option<Operation> {
+        let (numerator, current) = match self {
+            Divide(Some(a), Some(b)) => (*a.clone(), *b.clone()),
+            Rational(n, d) => (Value(*n as f64), Value(*d as f64)),
+            _ => (self.clone(), Value(1.0)),
+        };
+        let numeric = |x: &Operation| match x {
+            Value(_) | Rational(_, _) => Some(x.value()),
+            _ => None,
+        };
+        let numerator: Operation = match (numeric(&current), numeric(denominator)) {
+            (Some(a), Some(b)) => {
+                let factor: f64 = b / a;
+                if factor == 0.0 || !factor.is_finite() || factor.fract() != 0.0 {
+                    return None;
+                }
+                let (coefficient, base) = numerator.decompose_term();
+                match base {
+                    Value(1.0) => Value(coefficient * factor),
+                    base => Multiply(vec![Value(coefficient * factor), base]),
+                }
+            }
+            _ if *denominator == current => numerator,
+            _ => {
+                let Multiply(factors) = denominator else {
+                    return None;
+                };
+                let position: usize = factors.iter().position(|x| *x == current)?;
+                let mut factors: Vec<Operation> = factors.clone();
+                factors.remove(position);
+                if numerator != Value(1.0) {
+                    factors.push(numerator);
+                }
+                match factors.len() {
+                    1 => factors.remove(0),
+                    _ => Multiply(factors),
+                }
+            }
+        };
+        Some(Divide(
+            Some(Box::new(numerator)),
+            Some(Box::new(denominator.clone())),
+        ))
+    }
+
+    /// Returns `1 / self`.
+    ///
+    /// Fractions are flipped, so `1 / x` gives back `x`, and nonzero constants are inverted
+    /// directly. Anything else, including zero, is wrapped in a `Divide`.
+    pub fn reciprocal(&self) -> Operation {
+        match self {
+            Divide(Some(a), Some(b)) if **a == Value(1.0) => *b.clone(),
+            Divide(Some(a), Some(b)) => Divide(Some(b.clone()), Some(a.clone())),
+            Value(a) if *a != 0.0 => Value(1.0 / a),
+            Rational(a, b) if *a != 0 => Operation::rational(*b, *a),
+            _ => Divide(Some(Box::new(Value(1.0))), Some(Box::new(self.clone()))),
+        }
+    }
+
+    /// Renders the operation to LaTeX with the subtrees that differ from `previous`
+    /// wrapped in `\color{red}{...}`.
+    ///
+    /// The trees are walked in parallel, a node is considered changed when its type or
+    /// number of children differs, or when it is a leaf that renders differently.
+    pub fn latex_diff(&self, previous: &Operation) -> String {
+        let mut highlighted: Operation = self.clone();
+        highlight_changes(&mut highlighted, previous);
+        highlighted.latex_string()
+    }
+
+    /// Checks the symbolic derivative against a central finite difference.
+    ///
+    /// Both are evaluated at the point `at` with step `h` and must agree within `tol`.
+    /// Returns false if the operation cannot be differentiated or evaluated there.
+    pub fn check_derivative(&self, var: &str, at: &HashMap<String, f64>, h: f64, tol: f64) -> bool {
+        let shifted = |offset: f64| {
+            let mut bindings: HashMap<String, f64> = at.clone();
+            *bindings.entry(var.to_string()).or_insert(0.0) += offset;
+            self.evaluate(&bindings)
+        };
+        let analytic: Result<f64, String> = match self.derivative(var) {
+            Some(derivative) => derivative.evaluate(at),
+            None => return false,
+        };
+        match (analytic, shifted(h), shifted(-h)) {
+            (Ok(analytic), Ok(forward), Ok(backward)) => {
+                (analytic - (forward - backward) / (2.0 * h)).abs() <= tol
+            }
+            _ => false,
+        }
+    }
+
+    /// Checks that the tree is well formed: every operand is present and `Equal` only
+    /// appears at the root or as the condition of a `Piecewise` branch.
+    ///
+    /// Returns an error naming the first offending operation otherwise.
+    pub fn validate(&self) -> Result<(), String> {
+        let complete: bool = match self {
+            Negate(a) | Log(a) => a.is_some(),
+            Divide(a, b) | Equal(a, b) | Power(a, b) => a.is_some() && b.is_some(),
+            _ => true,
+        };
+        if !complete {
+            return Err(format!(
+                "{} is missing an operand",
+                self.print_operation_type()
+            ));
+        }
+        let nested = |x: &Operation| match x {
+            Equal(_, _) => Err(format!(
+                "Equal cannot be nested inside {}",
+                self.print_operation_type()
+            )),
+            x => x.validate(),
+        };
+        match self {
+            Piecewise(branches, default) => {
+                branches.iter().try_for_each(|(c, v)| {
+                    c.validate()?;
+                    nested(v)
+                })?;
+                default.iter().try_for_each(|x| nested(x))
+            }
+            _ => self.children().into_iter().try_for_each(nested),
+        }
+    }
+
+    /// Checks that scaling every symbol in `vars` by `t` scales the operation by
+    /// `t^degree`.
+    ///
+    /// The check samples a few points and scale factors, other symbols are held at fixed
+    /// sample values. Returns false if the operation cannot be evaluated at the samples.
+    pub fn is_homogeneous(&self, vars: &[String], degree: i32) -> bool {
+        let names: Vec<String> = self.sorted_variable_names();
+        [0.0, 1.0].iter().all(|offset| {
+            let point = |t: f64| -> HashMap<String, f64> {
+                names
+                    .iter()
+                    .enumerate()
+                    .map(|(i, x)| {
+                        let value: f64 = 0.7 + 0.3 * i as f64 + offset;
+                        let scale: f64 = if vars.contains(x) { t } else { 1.0 };
+                        (x.clone(), value * scale)
+                    })
+                    .collect()
+            };
+            let Ok(base) = self.evaluate(&point(1.0)) else {
+                return false;
+            };
+            [0.5, 2.0, 3.0]
+                .iter()
+                .all(|t| match self.evaluate(&point(*t)) {
+                    Ok(scaled) => {
+                        let expected: f64 = t.powi(degree) * base;
+                        (scaled - expected).abs() <= 1e-9 * expected.abs().max(1.0)
+                    }
+                    Err(_) => false,
+                })
+        })
+    }
+
+    /// Repeatedly simplifies and cleans up the operation until it stops changing.
+    pub(crate) fn simplify_fully(&self) -> Operation {
+        let mut output: Operation = self.clone();
+        for _ in 0..MAX_SIMPLIFY_STEPS {
+            let mut next: Operation = output.simplify().unwrap_or_else(|| output.clone());
+            next.cleanup();
+            if next.latex_string() == output.latex_string() {
+                break;
+            }
+            output = next;
+        }
+        output
+    }
+
+    /// Counts the operations in the tree keyed by `print_operation_type`.
+    pub fn operation_histogram(&self) -> HashMap<&'static str, usize> {
+        let mut histogram: HashMap<&'static str, usize> = HashMap::new();
+        self.walk(&mut |x| *histogram.entry(x.print_operation_type()).or_insert(0) += 1);
+        histogram
+    }
+
+    /// Finds the subexpressions that appear more than once in the tree.
+    ///
+    /// Leaves are ignored and subexpressions are identified by their `latex_string`. Each
+    /// repeated subexpression is returned once, in the order it is first visited, along with
+    /// the number of times it occurs.
+    pub fn common_subexpressions(&self) -> Vec<(Operation, usize)> {
+        let mut seen: Vec<(String, Operation, usize)> = Vec::new();
+        self.walk(&mut |x| {
+            if x.children().is_empty() {
+                return;
+            }
+            let key: String = x.latex_string();
+            match seen.iter_mut().find(|(k, _, _)| *k == key) {
+                Some((_, _, count)) => *count += 1,
+                None => seen.push((key, x.clone(), 1)),
+            }
+        });
+        seen.into_iter()
+            .filter(|(_, _, count)| *count > 1)
+            .map(|(_, x, count)| (x, count))
+            .collect()
+    }
+
+    /// Visits the operation and all of its descendants in pre-order.
+    fn walk<F: FnMut(&Operation)>(&self, f: &mut F) {
+        f(self);
+        self.children().into_iter().for_each(|x| x.walk(f));
+    }
+
+    /// Returns the value of the first branch of a `Piecewise` whose condition holds, or its
+    /// default if there is no such branch.
+    ///
+    /// An `Equal` condition holds when its sides have the same value, any other condition
+    /// when its value is a nonzero number.
+    ///
+    /// Returns `None` for other operations, when nothing matches without a default and when
+    /// a condition is a template, see `is_template`.
+    pub fn piecewise_branch(&self) -> Option<&Operation> {
+        match self {
+            Piecewise(branches, _) if branches.iter().any(|(c, _)| c.is_template()) => None,
+            Piecewise(branches, default) => branches
+                .iter()
+                .find(|(c, _)| condition_holds(c))
+                .map(|(_, v)| v)
+                .or(default.as_deref()),
+            _ => None,
+        }
+    }
+
+    /// Returns the two operands of a `Divide`, `Equal` or `Power`.
+    ///
+    /// Returns `None` for other operations and for incomplete ones missing an operand.
+    pub fn binary_operands(&self) -> Option<(&Operation, &Operation)> {
+        match self {
+            Divide(Some(a), Some(b)) | Equal(Some(a), Some(b)) | Power(Some(a), Some(b)) => {
+                Some((a, b))
+            }
+            _ => None,
+        }
+    }
+
+    /// Splits the operation into its kind and its children, in the order of `children`.
+    ///
+    /// Leaves and incomplete operations, with a missing operand, are returned whole as an
+    /// `OpKind::Leaf` without children. `Operation::reconstruct` reverses this.
+    pub fn deconstruct(&self) -> (OpKind, Vec<Operation>) {
+        let complete: bool = match self {
+            Negate(a) | Log(a) => a.is_some(),
+            Divide(a, b) | Equal(a, b) | Power(a, b) => a.is_some() && b.is_some(),
+            _ => !self.is_leaf(),
+        };
+        if !complete {
+            return (OpKind::Leaf(self.clone()), Vec::new());
+        }
+        let kind: OpKind = match self {
+            Multiply(_) => OpKind::Multiply,
+            Sum(_) => OpKind::Sum,
+            Negate(_) => OpKind::Negate,
+            Log(_) => OpKind::Log,
+            Divide(_, _) => OpKind::Divide,
+            Equal(_, _) => OpKind::Equal,
+            Power(_, _) => OpKind::Power,
+            Piecewise(_, default) => OpKind::Piecewise {
+                default: default.is_some(),
+            },
+            _ => unreachable!(),
+        };
+        (kind, self.children().into_iter().cloned().collect())
+    }
+
+    /// Builds an operation from its kind and children, see `Operation::deconstruct`.
+    ///
+    /// Panics if the number of children does not fit the kind.
+    pub fn reconstruct(kind: OpKind, children: Vec<Operation>) -> Operation {
+        let count: usize = children.len();
+        let mut children = children.into_iter();
+        let mut next = || children.next().map(Box::new);
+        match (kind, count) {
+            (OpKind::Leaf(a), 0) => a,
+            (OpKind::Multiply, _) => Multiply(children.collect()),
+            (OpKind::Sum, _) => Sum(children.collect()),
+            (OpKind::Negate, 1) => Negate(next()),
+            (OpKind::Log, 1) => Log(next()),
+            (OpKind::Divide, 2) => Divide(next(), next()),
+            (OpKind::Equal, 2) => Equal(next(), next()),
+            (OpKind::Power, 2) => Power(next(), next()),
+            (OpKind::Piecewise { default }, n) if n % 2 == default as usize => {
+                let branches: Vec<(Operation, Operation)> = (0..n / 2)
+                    .map(|_| (*next().unwrap(), *next().unwrap()))
+                    .collect();
+                Piecewise(branches, next())
+            }
+            (kind, n) => panic!("Cannot build {:?} from {} children", kind, n),
+        }
+    }
+
+    /// Returns true if the operation is a leaf that never holds child operations.
+    pub fn is_leaf(&self) -> bool {
+        matches!(
+            self,
+            Value(_) | Text(_) | Variable(_) | Mapping(_) | Rational(_, _) | Display(_)
+        )
+    }
+
+    /// Returns references to the immediate children of the operation.
+    ///
+    /// Children are listed in operand order, missing operands of incomplete operations
+    /// are skipped.
+    pub fn children(&self) -> Vec<&Operation> {
+        match self {
+            Multiply(list) | Sum(list) => list.iter().collect(),
+            Negate(a) | Log(a) => a.iter().map(|x| x.as_ref()).collect(),
+            Piecewise(branches, default) => branches
+                .iter()
+                .flat_map(|(c, v)| [c, v])
+                .chain(default.iter().map(|x| x.as_ref()))
+                .collect(),
+            Divide(a, b) | Equal(a, b) | Power(a, b) => {
+                a.iter().chain(b.iter()).map(|x| x.as_ref()).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns mutable references to the immediate children of the operation.
+    pub(crate) fn children_mut(&mut self) -> Vec<&mut Operation> {
+        match self {
+            Multiply(list) | Sum(list) => list.iter_mut().collect(),
+            Negate(a) | Log(a) => a.iter_mut().map(|x| x.as_mut()).collect(),
+            Divide(a, b) | Equal(a, b) | Power(a, b) => a
+                .iter_mut()
+                .chain(b.iter_mut())
+                .map(|x| x.as_mut())
+                .collect(),
+            Piecewise(branches, default) => branches
+                .iter_mut()
+                .flat_map(|(c, v)| [c, v])
+                .chain(default.iter_mut().map(|x| x.as_mut()))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Walks two trees in parallel for `Operation::alpha_eq`, extending the symbol renaming
+/// in both directions as new names are encountered.
+fn alpha_eq_with(
+    a: &Operation,
+    b: &Operation,
+    forward: &mut HashMap<String, String>,
+    backward: &mut HashMap<String, String>,
+) -> bool {
+    match (a, b) {
+        (Text(_) | Variable(_), Text(_) | Variable(_)) => {
+            let (x, y) = (a.equation_repr(), b.equation_repr());
+            let mapped: &String = forward.entry(x.clone()).or_insert_with(|| y.clone());
+            let unmapped: &String = backward.entry(y.clone()).or_insert(x.clone());
+            *mapped == y && *unmapped == x
+        }
+        _ => {
+            let (a_children, b_children) = (a.children(), b.children());
+            if a.print_operation_type() != b.print_operation_type()
+                || a_children.len() != b_children.len()
+            {
+                return false;
+            }
+            if a_children.is_empty() {
+                return a == b;
+            }
+            a_children
+                .iter()
+                .zip(b_children)
+                .all(|(x, y)| alpha_eq_with(x, y, forward, backward))
+        }
+    }
+}
+
+/// Replaces the subtrees of `current` that differ from `previous` with highlighted
+/// `Display` members, see `Operation::latex_diff`.
+fn highlight_changes(current: &mut Operation, previous: &Operation) {
+    let previous_children: Vec<&Operation> = previous.children();
+    let same_shape: bool = current.print_operation_type() == previous.print_operation_type()
+        && current.children().len() == previous_children.len();
+    if !same_shape
+        || (previous_children.is_empty() && current.latex_string() != previous.latex_string())
+    {
+        *current = Display(Shared::new(EquationRepr::new_with_latex(
+            current.equation_repr(),
+            format!("\\color{{red}}{{{}}}", current.latex_string()),
+            // Only rendered, and `value` panics on valid trees such as `Equal` or `Mapping`
+            f64::NAN,
+        )));
+        return;
+    }
+    for (x, y) in current.children_mut().into_iter().zip(previous_children) {
+        highlight_changes(x, y);
+    }
+}
+
+/// A fact about the value of a symbol, see `Assumptions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assumption {
+    NonZero,
+    /// Strictly positive, which implies `NonZero`.
+    Positive,
+}
+
+/// Per symbol facts used by `Operation::simplify_with_assumptions`.
+#[derive(Debug, Clone, Default)]
+pub struct Assumptions {
+    facts: HashMap<String, Vec<Assumption>>,
+}
+
+impl Assumptions {
+    pub fn new() -> Assumptions {
+        Assumptions::default()
+    }
+
+    /// Records `assumption` for the symbol `name`.
+    pub fn assume(&mut self, name: &str, assumption: Assumption) -> &mut Assumptions {
+        self.facts
+            .entry(name.to_string())
+            .or_default()
+            .push(assumption);
+        self
+    }
+
+    fn holds(&self, name: &str, assumption: Assumption) -> bool {
+        self.facts
+            .get(name)
+            .is_some_and(|x| x.contains(&assumption) || x.contains(&Assumption::Positive))
+    }
+
+    /// Returns true if the operation is known to be nonzero.
+    pub fn is_nonzero(&self, op: &Operation) -> bool {
+        match op {
+            Value(a) => *a != 0.0,
+            Rational(n, _) => *n != 0,
+            Text(_) | Variable(_) => self.holds(&op.equation_repr(), Assumption::NonZero),
+            Negate(Some(a)) | Power(Some(a), _) => self.is_nonzero(a),
+            Multiply(list) => list.iter().all(|x| self.is_nonzero(x)),
+            Divide(Some(a), Some(b)) => self.is_nonzero(a) && self.is_nonzero(b),
+            _ => false,
+        }
+    }
+
+    /// Returns true if the operation is known to be strictly positive.
+    pub fn is_positive(&self, op: &Operation) -> bool {
+        match op {
+            Value(a) => *a > 0.0,
+            Rational(n, _) => *n > 0,
+            Text(_) | Variable(_) => self.holds(&op.equation_repr(), Assumption::Positive),
+            Power(Some(a), _) => self.is_positive(a),
+            Multiply(list) => list.iter().all(|x| self.is_positive(x)),
+            Divide(Some(a), Some(b)) => self.is_positive(a) && self.is_positive(b),
+            _ => false,
+        }
+    }
+}
+
+/// Named subexpressions used by `Operation::expand_definitions`.
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    definitions: HashMap<String, Operation>,
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    /// Defines the symbol `name` as `definition`, replacing any previous definition.
+    pub fn define(&mut self, name: &str, definition: Operation) -> &mut Context {
+        self.definitions.insert(name.to_string(), definition);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Operation> {
+        self.definitions.get(name)
+    }
+}
+
+/// How `Operation::latex_string_with` renders divisions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FractionStyle {
+    /// `\frac{a}{b}`
+    #[default]
+    Frac,
+    /// `a/b`, with parentheses around compound operands, for inline text.
+    Slash,
+}
+
+/// Notation options for `Operation::latex_string_with`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatexStyle {
+    pub fractions: FractionStyle,
+}
+
+/// The kind of an operation without its children, see `Operation::deconstruct`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OpKind {
+    Multiply,
+    Sum,
+    Negate,
+    Divide,
+    Equal,
+    Power,
+    Log,
+    /// Children are the flattened `(condition, value)` pairs, then the default if any.
+    Piecewise {
+        default: bool,
+    },
+    /// An operation without children, kept whole.
+    Leaf(Operation),
+}
+
+/// Controls how eagerly `Operation::simplify_with` folds numeric divisions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SimplifyMode {
+    /// Only fold divisions with an integer result, keeping other fractions as `Divide`.
+    ExactOnly,
+    /// Fold every finite division into a `Value`.
+    #[default]
+    Numeric,
+}
+
+/// Optional rewrites for `Operation::simplify_with_options`, all disabled by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SimplifyOptions {
+    /// Rewrite division by a nonzero `Value` into multiplication by its reciprocal, so
+    /// `(2 * x) / 4` folds to `0.5 * x` instead of keeping the fraction.
+    pub divide_to_reciprocal: bool,
+    /// How numeric divisions are folded, see `SimplifyMode`.
+    pub mode: SimplifyMode,
+}
+
+/// A compiled operation, see `Operation::compile`. It takes one value per compiled symbol.
+pub type Compiled = Box<dyn Fn(&[f64]) -> f64>;
+
+/// Upper bound on the number of states returned by `Operation::simplify_steps`.
+const MAX_SIMPLIFY_STEPS: usize = 100;
+
+/// Highest degree collected by `Operation::as_polynomial`.
+pub const MAX_POLYNOMIAL_DEGREE: usize = 64;
+
+/// Checks whether a `Piecewise` condition holds, see `Operation::piecewise_branch`.
+fn condition_holds(condition: &Operation) -> bool {
+    match condition {
+        Equal(Some(a), Some(b)) => a.value() == b.value(),
+        _ => condition.value() != 0.0 && !condition.value().is_nan(),
+    }
+}
+
+/// Checks whether `child` has to be grouped when written as an operand of an operation
+/// with the given precedence, see `Operation::precedence`.
+///
+/// Sums and products of at most one term are written as that term, or as `0` and `1`.
+fn needs_grouping(child: &Operation, precedence: u8) -> bool {
+    match child {
+        Sum(list) | Multiply(list) if list.len() <= 1 => {
+            list.first().is_some_and(|x| needs_grouping(x, precedence))
+        }
+        _ => child.precedence() < precedence,
+    }
+}
+
+/// Writes `child` with `equation_repr`, in braces if it `needs_grouping`.
+fn grouped_repr(child: &Operation, precedence: u8) -> String {
+    if needs_grouping(child, precedence) {
+        format!("{{{}}}", child.equation_repr())
+    } else {
+        child.equation_repr()
+    }
+}
+
+/// Checks if a factor can follow a coefficient without a `\cdot`, a symbol or a power of one.
+fn is_symbolic_factor(op: &Operation) -> bool {
+    match op {
+        Text(_) | Variable(_) | Display(_) => true,
+        Power(Some(a), _) => matches!(**a, Text(_) | Variable(_) | Display(_)),
+        _ => false,
+    }
+}
+
+/// Checks whether two operations are the same base for the exponent laws.
+///
+/// `compare_structure` alone treats any two leaves as matching, so the rendered
+/// form is compared as well to make sure `x` is not merged with `y`.
+fn same_base(a: &Operation, b: &Operation) -> bool {
+    a.compare_structure(b) && a.latex_string() == b.latex_string()
+}
+
+/// Adds two exponents, folding them when both are numeric.
+fn add_exponents(a: Operation, b: Operation) -> Operation {
+    match (a, b) {
+        (Value(a), Value(b)) => Value(a + b),
+        (a, b) => Sum(vec![a, b]),
+    }
+}
+
+/// Subtracts two exponents, folding them when both are numeric.
+fn subtract_exponents(a: Operation, b: Operation) -> Operation {
+    match (a, b) {
+        (Value(a), Value(b)) => Value(a - b),
+        (a, b) => Sum(vec![a, Negate(Some(Box::new(b)))]),
+    }
+}
+
+/// Multiplies two exponents, folding them when both are numeric.
+fn multiply_exponents(a: Operation, b: Operation) -> Operation {
+    match (a, b) {
+        (Value(a), Value(b)) => Value(a * b),
+        (a, b) => Multiply(vec![a, b]),
+    }
+}
+
+/// Checks whether rewriting `(x^a)^b` to `x^(a * b)` would drop the sign of a negative `x`.
+///
+/// This is the case when `a` is an even integer but the combined exponent is not.
+fn loses_sign(inner_exponent: &Operation, exponent: &Operation) -> bool {
+    let even = |x: f64| x % 2.0 == 0.0;
+    match (inner_exponent, exponent) {
+        (Value(a), Value(b)) => even(*a) && !even(*b),
+        (Value(a), _) => even(*a),
+        _ => false,
+    }
+}
+
+/// Merges the `Power` factors of a product that share a base.
+///
+/// `x^a * x^b` becomes `x^(a + b)`, every other factor is left untouched.
+fn combine_powers(list: &[Operation]) -> Vec<Operation> {
+    let mut result: Vec<Operation> = Vec::new();
+    for item in list {
+        if let Power(Some(base), Some(exponent)) = item {
+            let existing = result.iter_mut().find(|x| match x {
+                Power(Some(other), Some(_)) => same_base(base, other),
+                _ => false,
+            });
+            if let Some(Power(_, Some(existing_exponent))) = existing {
+                **existing_exponent = add_exponents(*existing_exponent.clone(), *exponent.clone());
+                continue;
+            }
+        }
+        result.push(item.clone());
+    }
+    result
+}
+
+/// Simplified subtrees, keyed by their structural hash, with `None` for subtrees that
+/// cannot be simplified.
+type SimplifyCache = HashMap<u64, Vec<(Operation, Option<Operation>)>>;
+
+/// Simplifies every operation of a batch, sharing the simplified subtrees between them.
+///
+/// Every subtree visited while simplifying is cached by its structural hash, so a
+/// subexpression that appears in several operations is only simplified once. Operations
+/// that cannot be simplified are returned unchanged.
+pub fn simplify_batch(ops: &[Operation]) -> Vec<Operation> {
+    let mut cache: SimplifyCache = HashMap::new();
+    ops.iter()
+        .map(|x| {
+            x.simplify_in(SimplifyMode::default(), Some(&mut cache))
+                .unwrap_or_else(|| x.clone())
+        })
+        .collect()
+}
+
+/// Removes pairs of terms from a sum that cancel each other.
+///
+/// Terms are compared by their decomposition into coefficient and base, so both `x` and
+/// `-x` and `2 * x` and `-(2 * x)` cancel. Infinite coefficients never cancel, as
+/// `inf - inf` is NaN rather than zero.
+fn cancel_inverses(terms: &mut Vec<Operation>) {
+    let mut i: usize = 0;
+    while i < terms.len() {
+        let (coefficient, base) = terms[i].decompose_term();
+        let inverse = terms.iter().skip(i + 1).position(|x| {
+            let (other_coefficient, other_base) = x.decompose_term();
+            other_coefficient == -coefficient && same_base(&base, &other_base)
+        });
+        match inverse {
+            Some(j) if coefficient != 0.0 && coefficient.is_finite() => {
+                terms.remove(i + 1 + j);
+                terms.remove(i);
+            }
+            _ => i += 1,
+        }
+    }
+}
+
+/// Moves the `1 / d` factors of a product into the denominator of a single `Divide`.
+///
+/// `x * (1 / y) * (1 / z)` becomes `x / (y * z)`, with both products simplified. Returns
+/// `None` if there are no reciprocal factors.
+fn fold_reciprocals(list: &[Operation], mode: SimplifyMode) -> Option<Operation> {
+    let mut numerator: Vec<Operation> = Vec::new();
+    let mut denominator: Vec<Operation> = Vec::new();
+    for item in list {
+        match item {
+            Divide(Some(a), Some(b)) if matches!(a.as_ref(), Value(x) if *x == 1.0) => {
+                denominator.push(*b.clone())
+            }
+            _ => numerator.push(item.clone()),
+        }
+    }
+    if denominator.is_empty() {
+        return None;
+    }
+    let product = |mut list: Vec<Operation>| {
+        let product: Operation = match list.len() {
+            0 => Value(1.0),
+            1 => list.remove(0),
+            _ => Multiply(list),
+        };
+        product.simplify_with(mode).unwrap_or(product)
+    };
+    Some(Divide(
+        Some(Box::new(product(numerator))),
+        Some(Box::new(product(denominator))),
+    ))
+}
+
+/// A `(numerator, denominator)` pair used for exact `Rational` arithmetic.
+type Fraction = (i64, i64);
+
+/// Returns the exact fraction an operation represents, if any.
+///
+/// Integer `Value`s are treated as fractions over one.
+fn as_rational(op: &Operation) -> Option<Fraction> {
+    match op {
+        Rational(n, d) => Some((*n, *d)),
+        Value(a) if a.fract() == 0.0 && a.abs() < i64::MAX as f64 => Some((*a as i64, 1)),
+        Negate(Some(a)) => as_rational(a).and_then(|(n, d)| Some((n.checked_neg()?, d))),
+        _ => None,
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Reduces a fraction to lowest terms with a positive denominator.
+///
+/// Returns `None` if the denominator is zero or the reduced fraction does not fit in an
+/// `i64`.
+fn reduce_rational(numerator: i64, denominator: i64) -> Option<Fraction> {
+    if denominator == 0 {
+        return None;
+    }
+    let divisor: u64 = gcd(numerator.unsigned_abs(), denominator.unsigned_abs());
+    let (mut n, mut d) = (numerator as i128, denominator as i128);
+    n /= divisor as i128;
+    d /= divisor as i128;
+    if d < 0 {
+        (n, d) = (-n, -d);
+    }
+    Some((i64::try_from(n).ok()?, i64::try_from(d).ok()?))
+}
+
+/// Builds a reduced `Rational`, or a `Value` if it does not fit in an `i64`. Returns `None`
+/// if the denominator is zero.
+fn rational_or_value(numerator: i64, denominator: i64) -> Option<Operation> {
+    match reduce_rational(numerator, denominator) {
+        Some((n, d)) => Some(Rational(n, d)),
+        None if denominator != 0 => Some(Value(numerator as f64 / denominator as f64)),
+        None => None,
+    }
+}
+
+fn add_rationals(a: Fraction, b: Fraction) -> Option<Fraction> {
+    let numerator = a.0.checked_mul(b.1)?.checked_add(b.0.checked_mul(a.1)?)?;
+    reduce_rational(numerator, a.1.checked_mul(b.1)?)
+}
+
+fn multiply_rationals(a: Fraction, b: Fraction) -> Option<Fraction> {
+    reduce_rational(a.0.checked_mul(b.0)?, a.1.checked_mul(b.1)?)
+}
+
+fn divide_rationals(a: Fraction, b: Fraction) -> Option<Fraction> {
+    reduce_rational(a.0.checked_mul(b.1)?, a.1.checked_mul(b.0)?)
+}
+
+/// Folds a list of operands exactly when at least one is a `Rational` and all are exact.
+///
+/// Returns `None` if the operands are not all exact or the arithmetic overflows.
+fn fold_rationals(
+    list: &[Operation],
+    f: fn(Fraction, Fraction) -> Option<Fraction>,
+) -> Option<Operation> {
+    if !list.iter().any(|x| matches!(x, Rational(_, _))) {
+        return None;
+    }
+    let mut operands = list.iter().map(as_rational);
+    let mut result: Fraction = operands.next()??;
+    for operand in operands {
+        result = f(result, operand?)?;
+    }
+    Some(match result {
+        (n, 1) => Value(n as f64),
+        (n, d) => Rational(n, d),
+    })
+}
+
+/// Splits an S-expression into parentheses and atoms, keeping quoted atoms quoted.
+fn tokenize_sexpr(input: &str) -> Result<Vec<String>, String> {
+    let mut tokens: Vec<String> = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' | ')' => tokens.push(c.to_string()),
+            '"' => {
+                let mut token: String = String::from('"');
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => token.push(c),
+                            None => return Err("Unterminated quoted symbol".to_string()),
+                        },
+                        Some(c) => token.push(c),
+                        None => return Err("Unterminated quoted symbol".to_string()),
+                    }
+                }
+                token.push('"');
+                tokens.push(token);
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut token: String = String::from(c);
+                while let Some(next) = chars.peek() {
+                    if next.is_whitespace() || "()\"".contains(*next) {
+                        break;
+                    }
+                    token.push(chars.next().unwrap());
+                }
+                tokens.push(token);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses the expression starting at `position`, returning `None` for `nil`.
+fn parse_sexpr(tokens: &[String], position: &mut usize) -> Result<Option<Operation>, String> {
+    let token: &String = tokens.get(*position).ok_or("Unexpected end of input")?;
+    *position += 1;
+    if token == ")" {
+        return Err("Unexpected )".to_string());
+    }
+    if token != "(" {
+        return Ok(match token.as_str() {
+            "nil" => None,
+            quoted if quoted.starts_with('"') => {
+                Some(Text(quoted[1..quoted.len() - 1].to_string()))
+            }
+            atom => Some(
+                atom.parse::<f64>()
+                    .map_or_else(|_| Text(atom.to_string()), Value),
+            ),
+        });
+    }
+
+    let head: String = tokens
+        .get(*position)
+        .ok_or("Unexpected end of input")?
+        .clone();
+    *position += 1;
+    let mut arguments: Vec<Option<Operation>> = Vec::new();
+    while tokens.get(*position).map(|x| x.as_str()) != Some(")") {
+        if *position >= tokens.len() {
+            return Err("Missing )".to_string());
+        }
+        if matches!(head.as_str(), "map" | "rational") {
+            arguments.push(Some(Text(tokens[*position].clone())));
+            *position += 1;
+        } else {
+            arguments.push(parse_sexpr(tokens, position)?);
+        }
+    }
+    *position += 1;
+
+    let boxed = |x: &Option<Operation>| x.clone().map(Box::new);
+    let integer = |x: &Option<Operation>| match x {
+        Some(Text(a)) => a
+            .parse::<i64>()
+            .map_err(|_| format!("Expected an integer, found {}", a)),
+        _ => Err("Expected an integer".to_string()),
+    };
+    let operation: Operation = match (head.as_str(), arguments.as_slice()) {
+        ("+", _) | ("*", _) => {
+            let list: Vec<Operation> = arguments
+                .iter()
+                .cloned()
+                .collect::<Option<Vec<Operation>>>()
+                .ok_or(format!("Unexpected nil in ({})", head))?;
+            if head == "+" {
+                Sum(list)
+            } else {
+                Multiply(list)
+            }
+        }
+        ("-", [a]) => Negate(boxed(a)),
+        ("ln", [a]) => Log(boxed(a)),
+        ("/", [a, b]) => Divide(boxed(a), boxed(b)),
+        ("^", [a, b]) => Power(boxed(a), boxed(b)),
+        ("=", [a, b]) => Equal(boxed(a), boxed(b)),
+        ("map", [Some(Text(a))]) => Mapping(
+            a.parse::<usize>()
+                .map_err(|_| format!("Expected an index, found {}", a))?,
+        ),
+        ("rational", [n, d]) => {
+            let (n, d) = reduce_rational(integer(n)?, integer(d)?)
+                .ok_or("Rational denominator cannot be zero")?;
+            Rational(n, d)
+        }
+        ("piecewise", [branches @ .., default]) if branches.len() % 2 == 0 => Piecewise(
+            branches
+                .chunks(2)
+                .map(|x| Some((x[0].clone()?, x[1].clone()?)))
+                .collect::<Option<Vec<(Operation, Operation)>>>()
+                .ok_or("Unexpected nil in (piecewise)")?,
+            boxed(default),
+        ),
+        _ => {
+            return Err(format!(
+                "Invalid operator {} with {} arguments",
+                head,
+                arguments.len()
+            ))
+        }
+    };
+    Ok(Some(operation))
+}
+
+/// Extracts the symbolic coefficients of a polynomial in `var`, lowest degree first, for
+/// `Operation::as_polynomial`.
+///
+/// Like terms of the coefficients are collected as products are formed, so they stay small
+/// for high powers.
+fn polynomial_terms(op: &Operation, var: &str) -> Option<Vec<Operation>> {
+    let multiply = |a: &[Operation], b: &[Operation]| -> Option<Vec<Operation>> {
+        if a.len() + b.len() - 2 > MAX_POLYNOMIAL_DEGREE {
+            return None;
+        }
+        Some(
+            (0..a.len() + b.len() - 1)
+                .map(|k| {
+                    collect_terms(
+                        (0..a.len())
+                            .filter(|i| k >= *i && k - i < b.len())
+                            .flat_map(|i| {
+                                multiply_polynomials(&terms_of(&a[i]), &terms_of(&b[k - i]))
+                            })
+                            .collect(),
+                    )
+                })
+                .collect(),
+        )
+    };
+    if op.is_constant_in(var) {
+        return Some(vec![op.clone()]);
+    }
+    match op {
+        Text(_) | Variable(_) => Some(vec![Value(0.0), Value(1.0)]),
+        Negate(Some(a)) => Some(
+            polynomial_terms(a, var)?
+                .into_iter()
+                .map(|x| Negate(Some(Box::new(x))))
+                .collect(),
+        ),
+        Sum(list) => {
+            let mut sum: Vec<Vec<Operation>> = Vec::new();
+            for item in list {
+                for (i, x) in polynomial_terms(item, var)?.into_iter().enumerate() {
+                    match sum.get_mut(i) {
+                        Some(terms) => terms.push(x),
+                        None => sum.push(vec![x]),
+                    }
+                }
+            }
+            Some(sum.into_iter().map(Sum).collect())
+        }
+        Multiply(list) => list.iter().try_fold(vec![Value(1.0)], |product, x| {
+            multiply(&product, &polynomial_terms(x, var)?)
+        }),
+        Divide(Some(a), Some(b)) if b.is_constant_in(var) => Some(
+            polynomial_terms(a, var)?
+                .into_iter()
+                .map(|x| Divide(Some(Box::new(x)), Some(b.clone())))
+                .collect(),
+        ),
+        Power(Some(a), Some(b)) if b.is_constant_in(var) => {
+            let exponent: f64 = b.evaluate(&HashMap::new()).ok()?;
+            if !(0.0..=MAX_POLYNOMIAL_DEGREE as f64).contains(&exponent) || exponent.fract() != 0.0
+            {
+                return None;
+            }
+            let base: Vec<Operation> = polynomial_terms(a, var)?;
+            (0..exponent as usize).try_fold(vec![Value(1.0)], |x, _| multiply(&x, &base))
+        }
+        Equal(Some(a), Some(b)) => {
+            polynomial_terms(&Sum(vec![*a.clone(), Negate(Some(b.clone()))]), var)
+        }
+        _ => None,
+    }
+}
+
+/// A term of a polynomial as a coefficient and a list of `(base, exponent)` factors.
+type Monomial = (f64, Vec<(Operation, f64)>);
+
+/// Splits a term into its coefficient and factors without distributing nested sums.
+fn monomial_of(op: &Operation) -> Monomial {
+    match op {
+        Value(a) => (*a, Vec::new()),
+        Negate(Some(a)) => {
+            let (coefficient, factors) = monomial_of(a);
+            (-coefficient, factors)
+        }
+        Multiply(list) => list
+            .iter()
+            .map(monomial_of)
+            .fold((1.0, Vec::new()), |a, b| multiply_monomials(&a, &b)),
+        Power(Some(base), Some(exponent)) => match exponent.as_ref() {
+            Value(k) => (1.0, vec![(*base.clone(), *k)]),
+            _ => (1.0, vec![(op.clone(), 1.0)]),
+        },
+        _ => (1.0, vec![(op.clone(), 1.0)]),
+    }
+}
+
+fn multiply_monomials(a: &Monomial, b: &Monomial) -> Monomial {
+    let mut factors: Vec<(Operation, f64)> = a.1.clone();
+    for (base, exponent) in &b.1 {
+        match factors.iter_mut().find(|(x, _)| same_base(x, base)) {
+            Some((_, existing)) => *existing += exponent,
+            None => factors.push((base.clone(), *exponent)),
+        }
+    }
+    factors.retain(|(_, exponent)| *exponent != 0.0);
+    (a.0 * b.0, factors)
+}
+
+/// Multiplies two polynomials term by term and collects like terms.
+fn multiply_polynomials(a: &[Monomial], b: &[Monomial]) -> Vec<Monomial> {
+    let mut result: Vec<(String, Monomial)> = Vec::new();
+    for x in a {
+        for y in b {
+            let product: Monomial = multiply_monomials(x, y);
+            let mut key: Vec<String> = product
+                .1
+                .iter()
+                .map(|(base, exponent)| format!("{}^{}", base.latex_string(), exponent))
+                .collect();
+            key.sort();
+            let key: String = key.join("*");
+            match result.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, existing)) => existing.0 += product.0,
+                None => result.push((key, product)),
+            }
+        }
+    }
+    result
+        .into_iter()
+        .map(|(_, x)| x)
+        .filter(|(coefficient, _)| *coefficient != 0.0)
+        .collect()
+}
+
+/// Splits an operation into the monomials of its (possibly nested) sums.
+fn terms_of(op: &Operation) -> Vec<Monomial> {
+    match op {
+        Sum(list) => list.iter().flat_map(terms_of).collect(),
+        Negate(Some(a)) => terms_of(a)
+            .into_iter()
+            .map(|(coefficient, factors)| (-coefficient, factors))
+            .collect(),
+        _ => vec![monomial_of(op)],
+    }
+}
+
+/// Collects like monomials into a single sum.
+fn collect_terms(terms: Vec<Monomial>) -> Operation {
+    let mut terms: Vec<Operation> = multiply_polynomials(&terms, &[(1.0, Vec::new())])
+        .into_iter()
+        .map(monomial_to_operation)
+        .collect();
+    match terms.len() {
+        0 => Value(0.0),
+        1 => terms.remove(0),
+        _ => Sum(terms),
+    }
+}
+
+fn monomial_to_operation((coefficient, factors): Monomial) -> Operation {
+    let mut list: Vec<Operation> = factors
+        .into_iter()
+        .map(|(base, exponent)| {
+            if exponent == 1.0 {
+                base
+            } else {
+                Power(Some(Box::new(base)), Some(Box::new(Value(exponent))))
+            }
+        })
+        .collect();
+    if coefficient != 1.0 || list.is_empty() {
+        list.insert(0, Value(coefficient));
+    }
+    if list.len() == 1 {
+        return list.remove(0);
+    }
+    Multiply(list)
+}
+
+impl Debug for Operation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.equation_repr())
+    }
+}
+
+impl PartialEq for Operation {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            // NaN is considered equal to itself so identical trees compare equal
+            (Value(a), Value(b)) => a.value() == b.value() || (a.is_nan() && b.is_nan()),
+            (Text(a), Text(b)) => a == b,
+            (Multiply(a), Multiply(b)) => same_elements(a, b),
+            (Negate(a), Negate(b)) | (Log(a), Log(b)) => a == b,
+            (Divide(a, b), Divide(c, d)) => a == c && b == d,
+            (Power(a, b), Power(c, d)) => a == c && b == d,
+            (Sum(a), Sum(b)) => same_elements(a, b),
+            (Mapping(a), Mapping(b)) => a == b,
+            (Rational(a, b), Rational(c, d)) => a == c && b == d,
+            (Piecewise(a, b), Piecewise(c, d)) => a == c && b == d,
+            (Equal(a, b), Equal(c, d)) => a == c && b == d,
+            (Variable(a), Variable(b)) => a.equation_repr() == b.equation_repr(),
+            (Display(a), Display(b)) => a.equation_repr() == b.equation_repr(),
+            _ => false,
+        }
+    }
+}
+
+/// Checks whether two lists hold the same operations regardless of order.
+fn same_elements(a: &[Operation], b: &[Operation]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut used: Vec<bool> = vec![false; b.len()];
+    a.iter().all(
+        |x| match b.iter().enumerate().find(|(i, y)| !used[*i] && x == *y) {
+            Some((i, _)) => {
+                used[i] = true;
+                true
+            }
+            None => false,
+        },
+    )
+}
+
+/// Structural hash consistent with `PartialEq`.
+///
+/// The terms of a `Sum` or `Multiply` are hashed independently and sorted so their order
+/// does not matter, mirroring the order-insensitive equality.
+impl Hash for Operation {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Multiply(list) | Sum(list) => {
+                let mut hashes: Vec<u64> = list
+                    .iter()
+                    .map(|x| {
+                        let mut hasher = DefaultHasher::new();
+                        x.hash(&mut hasher);
+                        hasher.finish()
+                    })
+                    .collect();
+                hashes.sort_unstable();
+                hashes.hash(state);
+            }
+            Negate(a) | Log(a) => a.hash(state),
+            Divide(a, b) | Equal(a, b) | Power(a, b) => {
+                a.hash(state);
+                b.hash(state);
+            }
+            // 0.0 and -0.0 are equal so they must hash the same
+            Value(a) => match a {
+                a if a.is_nan() => f64::NAN.to_bits().hash(state),
+                a if *a == 0.0 => 0.0f64.to_bits().hash(state),
+                a => a.to_bits().hash(state),
+            },
+            Text(a) => a.hash(state),
+            Mapping(a) => a.hash(state),
+            Variable(a) | Display(a) => a.equation_repr().hash(state),
+            Rational(a, b) => (a, b).hash(state),
+            Piecewise(branches, default) => {
+                branches.hash(state);
+                default.hash(state);
+            }
+        }
+    }
+}
+
+impl Add for Operation {
+    type Output = Operation;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        match (self, rhs) {
+            (Sum(mut a), Sum(b)) => {
+                b.into_iter().for_each(|x| add_term(&mut a, x, false));
+                Sum(a)
+            }
+            (Sum(mut a), b) => {
+                add_term(&mut a, b, false);
+                Sum(a)
+            }
+            (a, Sum(mut b)) => {
+                add_term(&mut b, a, true);
+                Sum(b)
+            }
+            (Value(a), Value(b)) => Value(a.value() + b.value()),
+            (a, b) => Sum(vec![a, b]),
+        }
+    }
+}
+
+/// Adds `term` to the terms of a sum, merging it into an existing `Value` term if both are
+/// values. Otherwise it is appended, or inserted at the front if `front` is set.
+fn add_term(terms: &mut Vec<Operation>, term: Operation, front: bool) {
+    if let Value(b) = term {
+        if let Some(Value(a)) = terms.iter_mut().find(|x| matches!(x, Value(_))) {
+            *a += b;
+            return;
+        }
+    }
+    if front {
+        terms.insert(0, term);
+    } else {
+        terms.push(term);
+    }
+}
+
+impl Index<usize> for Operation {
+    type Output = Operation;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        match self {
+            Multiply(a) => a.get(index).unwrap(),
+            Sum(a) => a.get(index).unwrap(),
+            _ => panic!("Cannot Index This Operation"),
+        }
+    }
+}
+
+impl num_traits::Zero for Operation {
+    fn zero() -> Self {
+        Value(0.0)
+    }
+
+    fn is_zero(&self) -> bool {
+        match self {
+            Variable(a) => a.is_zero(),
+            Value(a) => *a == 0.0,
+            _ => false,
+        }
+    }
+}
+
+impl Default for Operation {
+    fn default() -> Self {
+        Value(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mappings::RewriteRule;
+    use crate::math::EquationMember;
+    use crate::prelude::*;
+    use num_complex::Complex;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::{HashMap, HashSet};
+    use std::hash::{Hash, Hasher};
+
+    /// Returns the absolute difference between the values of `original` and its
+    /// simplification at `bindings`, or NaN if either cannot be evaluated.
+    fn simplification_error(original: &Operation, bindings: &HashMap<String, f64>) -> f64 {
+        let simplified: Operation = original.simplify().unwrap_or_else(|| original.clone());
+        match (original.evaluate(bindings), simplified.evaluate(bindings)) {
+            (Ok(a), Ok(b)) => (a - b).abs(),
+            _ => f64::NAN,
+        }
+    }
+
+    #[test]
+    fn test_multiplication_simplification() {
+        let a: Operation = Multiply(vec![Value(2.0), Value(3.0)]);
+        assert_eq!(a.simplify(), Some(Value(6.0)));
+
+        let a: Operation = Multiply(vec![Value(2.0), Value(3.0), Value(4.0)]);
+        assert_eq!(a.simplify(), Some(Value(24.0)));
+
+        let a: Operation = Multiply(vec![Value(2.0), Value(3.0), Text("x".to_string())]);
+        assert_eq!(
+            a.simplify(),
+            Some(Multiply(vec![Value(6.0), Text("x".to_string())]))
+        );
+
+        let a: Operation = Multiply(vec![
+            Value(3.0),
+            Value(2.0),
+            Text("x".to_string()),
+            Text("y".to_string()),
+        ]);
+        assert_eq!(
+            a.simplify(),
+            Some(Multiply(vec![
+                Value(6.0),
+                Text("x".to_string()),
+                Text("y".to_string())
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_negation_simplification() {
+        let a: Operation = Negate(Some(Box::new(Value(2.0))));
+        assert_eq!(a.simplify(), Some(Value(-2.0)));
+
+        let a: Operation = Negate(Some(Box::new(Negate(Some(Box::new(Value(2.0)))))));
+        assert_eq!(a.simplify(), Some(Value(2.0)));
+
+        let a: Operation = Negate(Some(Box::new(Multiply(vec![Value(2.0), Value(3.0)]))));
+        assert_eq!(a.simplify(), Some(Value(-6.0)));
+
+        let a: Operation = Negate(Some(Box::new(Multiply(vec![Value(2.0)]))));
+        assert_eq!(a.simplify(), Some(Value(-2.0)));
+    }
+
+    #[test]
+    fn test_division_simplification() {
+        let a: Operation = Divide(Some(Box::new(Value(2.0))), Some(Box::new(Value(3.0))));
+        assert_eq!(a.simplify(), Some(Value(2.0 / 3.0)));
+
+        let a: Operation = Divide(
+            Some(Box::new(Text("x".to_string()))),
+            Some(Box::new(Value(3.0))),
+        );
+        assert_eq!(
+            a.simplify(),
+            Some(Divide(
+                Some(Box::new(Text("x".to_string()))),
+                Some(Box::new(Value(3.0)))
+            ))
+        );
+
+        let a: Operation = Divide(
+            Some(Box::new(Value(2.0))),
+            Some(Box::new(Text("x".to_string()))),
+        );
+        assert_eq!(
+            a.simplify(),
+            Some(Divide(
+                Some(Box::new(Value(2.0))),
+                Some(Box::new(Text("x".to_string())))
+            ))
+        );
+
+        // There is no simplification for x/y thus return None
+        let a: Operation = Divide(
+            Some(Box::new(Text("x".to_string()))),
+            Some(Box::new(Text("y".to_string()))),
+        );
+        assert_eq!(a.simplify(), None);
+
+        let a: Operation = Divide(
+            Some(Box::new(Negate(Some(Box::new(Multiply(vec![
+                Value(2.0),
+                Value(3.0),
+            ])))))),
+            Some(Box::new(Text("x".to_string()))),
+        );
+        assert_eq!(
+            a.simplify(),
+            Some(Divide(
+                Some(Box::new(Value(-6.0))),
+                Some(Box::new(Text("x".to_string())))
+            ))
+        );
+
+        let a: Operation = Divide(
+            Some(Box::new(Negate(Some(Box::new(Multiply(vec![
+                Value(2.0),
+                Value(3.0),
+            ])))))),
+            Some(Box::new(Negate(Some(Box::new(Value(2.0)))))),
+        );
+        assert_eq!(a.simplify(), Some(Value(3.0)));
+
+        let a: Operation = Divide(
+            Some(Box::new(Negate(Some(Box::new(Multiply(vec![
+                Text("x".to_string()),
+                Value(2.0),
+            ])))))),
+            Some(Box::new(Negate(Some(Box::new(Value(2.0)))))),
+        );
+        assert_eq!(
+            a.simplify(),
+            Some(Divide(
+                Some(Box::new(Negate(Some(Box::new(Multiply(vec![
+                    Text("x".to_string()),
+                    Value(2.0),
+                ])))))),
+                Some(Box::new(Value(-2.0)))
+            ),)
+        );
+    }
+
+    #[test]
+    fn test_summation_simplification() {
+        let a: Operation = Sum(vec![Value(2.0), Value(3.0)]);
+        assert_eq!(a.simplify(), Some(Value(5.0)));
+
+        let a: Operation = Sum(vec![Value(2.0), Value(3.0), Value(4.0)]);
+        assert_eq!(a.simplify(), Some(Value(9.0)));
+
+        let a: Operation = Sum(vec![Value(2.0), Value(3.0), Text("x".to_string())]);
+        assert_eq!(
+            a.simplify(),
+            Some(Sum(vec![Value(5.0), Text("x".to_string())]))
+        );
+
+        let a: Operation = Sum(vec![
+            Value(2.0),
+            Value(3.0),
+            Text("x".to_string()),
+            Text("y".to_string()),
+        ]);
+        assert_eq!(
+            a.simplify(),
+            Some(Sum(vec![
+                Value(5.0),
+                Text("x".to_string()),
+                Text("y".to_string())
+            ]))
+        );
+
+        let a: Operation = Sum(vec![
+            Value(2.0),
+            Value(3.0),
+            Text("x".to_string()),
+            Text("y".to_string()),
+            Value(4.0),
+        ]);
+        assert_eq!(
+            a.simplify(),
+            Some(Sum(vec![
+                Value(9.0),
+                Text("x".to_string()),
+                Text("y".to_string())
+            ]))
+        );
+
+        let a: Operation = Sum(vec![
+            Value(0.0),
+            Text("x".to_string()),
+            Text("y".to_string()),
+        ]);
+        assert_eq!(
+            a.simplify(),
+            Some(Sum(vec![Text("x".to_string()), Text("y".to_string())]))
+        );
+
+        let a: Operation = Sum(vec![Value(0.0), Text("x".to_string())]);
+        assert_eq!(a.simplify(), Some(Text("x".to_string())));
+    }
+
+    #[test]
+    fn test_get_coefficient() {
+        let a: Operation = Divide(
+            Some(Box::new(Text("x".to_string()))),
+            Some(Box::new(Value(3.0))),
+        );
+        assert_eq!(a.get_coefficient(), Some(1.0 / 3.0));
+
+        let a: Operation = Divide(
+            Some(Box::new(Value(2.0))),
+            Some(Box::new(Text("x".to_string()))),
+        );
+        assert_eq!(a.get_coefficient(), Some(2.0));
+
+        let a: Operation = Divide(
+            Some(Box::new(Negate(Some(Box::new(Multiply(vec![
+                Value(2.0),
+                Value(3.0),
+            ])))))),
+            Some(Box::new(Text("x".to_string()))),
+        );
+        assert_eq!(a.get_coefficient(), Some(-6.0));
+
+        let a: Operation = Multiply(vec![Value(2.0), Value(3.0), Text("x".to_string())]);
+        assert_eq!(a.get_coefficient(), Some(6.0));
+    }
+
+    #[test]
+    fn test_exponent_laws() {
+        let x = || Box::new(Text("x".to_string()));
+        let a = || Text("a".to_string());
+        let b = || Text("b".to_string());
+
+        // x^2 * x^3 = x^5
+        let op: Operation = Multiply(vec![
+            Power(Some(x()), Some(Box::new(Value(2.0)))),
+            Power(Some(x()), Some(Box::new(Value(3.0)))),
+        ]);
+        assert_eq!(
+            op.simplify(),
+            Some(Power(Some(x()), Some(Box::new(Value(5.0)))))
+        );
+
+        // x^a * x^b = x^(a + b)
+        let op: Operation = Multiply(vec![
+            Power(Some(x()), Some(Box::new(a()))),
+            Power(Some(x()), Some(Box::new(b()))),
+        ]);
+        assert_eq!(
+            op.simplify(),
+            Some(Power(Some(x()), Some(Box::new(Sum(vec![a(), b()])))))
+        );
+
+        // Different bases are not merged
+        let op: Operation = Multiply(vec![
+            Power(Some(x()), Some(Box::new(Value(2.0)))),
+            Power(
+                Some(Box::new(Text("y".to_string()))),
+                Some(Box::new(Value(3.0))),
+            ),
+        ]);
+        assert_eq!(
+            op.simplify(),
+            Some(Multiply(vec![
+                Power(Some(x()), Some(Box::new(Value(2.0)))),
+                Power(
+                    Some(Box::new(Text("y".to_string()))),
+                    Some(Box::new(Value(3.0)))
+                ),
+            ]))
+        );
+
+        // (x^2)^3 = x^6
+        let op: Operation = Power(
+            Some(Box::new(Power(Some(x()), Some(Box::new(Value(2.0)))))),
+            Some(Box::new(Value(3.0))),
+        );
+        assert_eq!(
+            op.simplify(),
+            Some(Power(Some(x()), Some(Box::new(Value(6.0)))))
+        );
+
+        // (x^a)^b = x^(a * b)
+        let op: Operation = Power(
+            Some(Box::new(Power(Some(x()), Some(Box::new(a()))))),
+            Some(Box::new(b())),
+        );
+        assert_eq!(
+            op.simplify(),
+            Some(Power(Some(x()), Some(Box::new(Multiply(vec![a(), b()])))))
+        );
+
+        // x^5 / x^2 = x^3
+        let op: Operation = Divide(
+            Some(Box::new(Power(Some(x()), Some(Box::new(Value(5.0)))))),
+            Some(Box::new(Power(Some(x()), Some(Box::new(Value(2.0)))))),
+        );
+        assert_eq!(
+            op.simplify(),
+            Some(Power(Some(x()), Some(Box::new(Value(3.0)))))
+        );
+
+        // x^a / x^b = x^(a - b)
+        let op: Operation = Divide(
+            Some(Box::new(Power(Some(x()), Some(Box::new(a()))))),
+            Some(Box::new(Power(Some(x()), Some(Box::new(b()))))),
+        );
+        assert_eq!(
+            op.simplify(),
+            Some(Power(
+                Some(x()),
+                Some(Box::new(Sum(vec![a(), Negate(Some(Box::new(b())))])))
+            ))
+        );
+
+        // Numeric powers fold completely
+        let op: Operation = Power(Some(Box::new(Value(2.0))), Some(Box::new(Value(3.0))));
+        assert_eq!(op.simplify(), Some(Value(8.0)));
+    }
+
+    #[test]
+    fn test_simplify_steps() {
+        let a: Operation = Sum(vec![
+            Multiply(vec![Value(2.0), Value(3.0)]),
+            Negate(Some(Box::new(Negate(Some(Box::new(Value(4.0))))))),
+            Text("x".to_string()),
+        ]);
+        let steps: Vec<Operation> = a.simplify_steps();
+        assert!(steps.len() > 2);
+        assert_eq!(steps.first(), Some(&a));
+        assert_eq!(
+            steps[1],
+            Sum(vec![
+                Value(6.0),
+                Negate(Some(Box::new(Negate(Some(Box::new(Value(4.0))))))),
+                Text("x".to_string()),
+            ])
+        );
+        assert_eq!(
+            steps.last(),
+            Some(&Sum(vec![Text("x".to_string()), Value(10.0)]))
+        );
+
+        // Already simplified operations only produce themselves
+        let a: Operation = Text("x".to_string());
+        assert_eq!(a.simplify_steps(), vec![a]);
+    }
+
+    #[test]
+    fn test_named_variable() {
+        let x: Operation = Operation::variable("x");
+        assert_eq!(x.equation_repr(), "x");
+        assert_eq!(x.latex_string(), "x");
+        assert!(x.value().is_nan());
+
+        // Unbound variables are left in place
+        let mut a: Operation = Multiply(vec![Value(2.0), x.clone()]);
+        a.apply_variables();
+        assert_eq!(a.equation_repr(), "2 * x");
+
+        let mut a: Operation = Multiply(vec![Value(2.0), Operation::variable_with_value("x", 3.0)]);
+        assert_eq!(a.value(), 6.0);
+        a.apply_variables();
+        assert_eq!(a, Multiply(vec![Value(2.0), Value(3.0)]));
+
+        // Text and Variable symbols with the same name are the same variable
+        let a: Operation = Sum(vec![x, Text("x".to_string()), Text("y".to_string())]);
+        let names: Vec<String> = a
+            .get_variables()
+            .iter()
+            .map(|x| x.equation_repr())
+            .collect();
+        assert_eq!(names, vec!["x".to_string(), "y".to_string()]);
+    }
+
+    #[test]
+    fn test_linear_coefficients() {
+        let vars: Vec<String> = vec!["x".to_string(), "y".to_string()];
+        let a: Operation = Sum(vec![
+            Multiply(vec![Value(3.0), Text("x".to_string())]),
+            Multiply(vec![Value(2.0), Text("y".to_string())]),
+            Negate(Some(Box::new(Value(5.0)))),
+        ]);
+        assert_eq!(a.linear_coefficients(&vars), Some((vec![3.0, 2.0], -5.0)));
+
+        let a: Operation = Divide(
+            Some(Box::new(Sum(vec![
+                Text("y".to_string()),
+                Operation::variable_with_value("R", 4.0),
+            ]))),
+            Some(Box::new(Value(2.0))),
+        );
+        assert_eq!(a.linear_coefficients(&vars), Some((vec![0.0, 0.5], 2.0)));
+
+        // x * y is not linear
+        let a: Operation = Multiply(vec![Text("x".to_string()), Text("y".to_string())]);
+        assert_eq!(a.linear_coefficients(&vars), None);
+
+        // Unknown symbols without a value cannot be expressed as a coefficient
+        let a: Operation = Multiply(vec![Text("z".to_string()), Text("x".to_string())]);
+        assert_eq!(a.linear_coefficients(&vars), None);
+
+        // Dividing by a constant zero has no linear form
+        let a: Operation = Divide(
+            Some(Box::new(Text("x".to_string()))),
+            Some(Box::new(Value(0.0))),
+        );
+        assert_eq!(a.linear_coefficients(&vars), None);
+        let r: Operation = Operation::variable_with_value("R", 4.0);
+        let a: Operation = Divide(
+            Some(Box::new(Value(1.0))),
+            Some(Box::new(Sum(vec![r.clone(), Negate(Some(Box::new(r)))]))),
+        );
+        assert_eq!(a.linear_coefficients(&vars), None);
+    }
+
+    #[test]
+    fn test_simplify_approx() {
+        let a: Operation = Sum(vec![Text("x".to_string()), Value(1e-16)]);
+        assert_eq!(
+            a.simplify(),
+            Some(Sum(vec![Text("x".to_string()), Value(1e-16)]))
+        );
+        assert_eq!(a.simplify_approx(1e-12), Text("x".to_string()));
+
+        // Residuals produced by folding are snapped as well
+        let a: Operation = Sum(vec![
+            Value(0.1),
+            Value(0.2),
+            Negate(Some(Box::new(Value(0.3)))),
+        ]);
+        assert_ne!(a.simplify(), Some(Value(0.0)));
+        assert_eq!(a.simplify_approx(1e-12), Value(0.0));
+
+        // Values above the threshold are kept
+        let a: Operation = Sum(vec![Text("x".to_string()), Value(1e-3)]);
+        assert_eq!(
+            a.simplify_approx(1e-12),
+            Sum(vec![Text("x".to_string()), Value(1e-3)])
+        );
+    }
+
+    #[test]
+    fn test_operation_histogram() {
+        let a: Operation = Sum(vec![
+            Multiply(vec![Value(3.0), Text("x".to_string())]),
+            Multiply(vec![Value(2.0), Text("y".to_string())]),
+            Divide(
+                Some(Box::new(Text("x".to_string()))),
+                Some(Box::new(Negate(Some(Box::new(Value(4.0)))))),
+            ),
+        ]);
+        let histogram = a.operation_histogram();
+        assert_eq!(histogram.get("Sum"), Some(&1));
+        assert_eq!(histogram.get("Multiply"), Some(&2));
+        assert_eq!(histogram.get("Divide"), Some(&1));
+        assert_eq!(histogram.get("Negate"), Some(&1));
+        assert_eq!(histogram.get("Value"), Some(&3));
+        assert_eq!(histogram.get("Text"), Some(&3));
+        assert_eq!(histogram.get("Power"), None);
+        assert_eq!(histogram.values().sum::<usize>(), 11);
+    }
+
+    #[test]
+    fn test_incomplete_simplification() {
+        let x = || Some(Box::new(Text("x".to_string())));
+
+        assert_eq!(Negate(None).simplify(), Some(Value(0.0)));
+        assert_eq!(Divide(None, x()).simplify(), Some(Value(0.0)));
+        assert_eq!(Divide(None, None).simplify(), Some(Value(0.0)));
+        assert_eq!(Divide(x(), None).simplify(), Some(Text("x".to_string())));
+        assert_eq!(
+            Divide(Some(Box::new(Multiply(vec![Value(2.0), Value(3.0)]))), None).simplify(),
+            Some(Value(6.0))
+        );
+        assert_eq!(Power(x(), None).simplify(), Some(Text("x".to_string())));
+        assert_eq!(Power(None, x()).simplify(), Some(Value(0.0)));
+
+        let a: Operation = Equal(x(), None).simplify().unwrap();
+        assert_eq!(a.equation_repr(), "x = 0");
+        let a: Operation = Equal(None, Some(Box::new(Sum(vec![Value(1.0), Value(2.0)]))))
+            .simplify()
+            .unwrap();
+        assert_eq!(a.equation_repr(), "0 = 3");
+    }
+
+    #[test]
+    fn test_rational_simplification() {
+        assert_eq!(Operation::rational(2, 4), Rational(1, 2));
+        assert_eq!(Operation::rational(3, -9), Rational(-1, 3));
+        assert_eq!(Rational(1, 3).equation_repr(), "1/3");
+        assert_eq!(Rational(1, 3).latex_string(), "\\frac{1}{3}");
+        assert_eq!(Rational(1, 4).value(), 0.25);
+
+        // Fractions that do not fit in an i64 fall back to a Value
+        assert_eq!(Operation::rational(i64::MIN, -1), Value(-(i64::MIN as f64)));
+        assert_eq!(
+            Operation::rational(1, i64::MIN),
+            Value(1.0 / i64::MIN as f64)
+        );
+        assert_eq!(Operation::rational(i64::MIN, i64::MIN), Rational(1, 1));
+        assert_eq!(
+            Rational(-1, i64::MIN).reciprocal(),
+            Value(-(i64::MIN as f64))
+        );
+        // Fractions built directly are reduced by simplify
+        assert_eq!(Rational(2, 4).simplify(), Some(Rational(1, 2)));
+        assert_eq!(Rational(1, 2).simplify(), None);
+        assert_eq!(Rational(1, 0).simplify(), None);
+
+        let a: Operation = Sum(vec![Operation::rational(1, 3), Operation::rational(1, 3)]);
+        assert_eq!(a.simplify(), Some(Rational(2, 3)));
+
+        let a: Operation = Sum(vec![
+            Operation::rational(1, 3),
+            Operation::rational(1, 3),
+            Operation::rational(1, 3),
+        ]);
+        assert_eq!(a.simplify(), Some(Value(1.0)));
+
+        let a: Operation = Sum(vec![
+            Operation::rational(1, 2),
+            Value(2.0),
+            Negate(Some(Box::new(Operation::rational(1, 3)))),
+        ]);
+        assert_eq!(a.simplify(), Some(Rational(13, 6)));
+
+        let a: Operation = Multiply(vec![Operation::rational(2, 3), Value(6.0)]);
+        assert_eq!(a.simplify(), Some(Value(4.0)));
+
+        let a: Operation = Divide(
+            Some(Box::new(Operation::rational(1, 2))),
+            Some(Box::new(Operation::rational(3, 4))),
+        );
+        assert_eq!(a.simplify(), Some(Rational(2, 3)));
+
+        // Without a Rational operand numeric folding is unchanged
+        let a: Operation = Divide(Some(Box::new(Value(2.0))), Some(Box::new(Value(3.0))));
+        assert_eq!(a.simplify(), Some(Value(2.0 / 3.0)));
+
+        // Mixing in symbols keeps the fraction as a separate term
+        let a: Operation = Sum(vec![Operation::rational(1, 3), Text("x".to_string())]);
+        assert_eq!(
+            a.simplify(),
+            Some(Sum(vec![Rational(1, 3), Text("x".to_string())]))
+        );
+    }
+
+    #[test]
+    fn test_fill_mappings() {
+        let template: Operation = Divide(
+            Some(Box::new(Sum(vec![Mapping(0), Mapping(1)]))),
+            Some(Box::new(Negate(Some(Box::new(Mapping(2)))))),
+        );
+
+        let values: Vec<Operation> = vec![
+            Text("x".to_string()),
+            Value(2.0),
+            Multiply(vec![Value(3.0), Text("y".to_string())]),
+        ];
+        assert_eq!(
+            template.fill_mappings(&values),
+            Divide(
+                Some(Box::new(Sum(vec![Text("x".to_string()), Value(2.0)]))),
+                Some(Box::new(Negate(Some(Box::new(Multiply(vec![
+                    Value(3.0),
+                    Text("y".to_string())
+                ])))))),
+            )
+        );
+
+        // Missing values leave the mapping in place
+        let values: Vec<Operation> = vec![Text("x".to_string())];
+        assert_eq!(
+            template.fill_mappings(&values),
+            Divide(
+                Some(Box::new(Sum(vec![Text("x".to_string()), Mapping(1)]))),
+                Some(Box::new(Negate(Some(Box::new(Mapping(2)))))),
+            )
+        );
+    }
+
+    #[test]
+    fn test_common_subexpressions() {
+        let sum = || Sum(vec![Text("a".to_string()), Text("b".to_string())]);
+        let a: Operation = Multiply(vec![
+            sum(),
+            Divide(Some(Box::new(sum())), Some(Box::new(Text("c".to_string())))),
+        ]);
+        assert_eq!(a.common_subexpressions(), vec![(sum(), 2)]);
+
+        let a: Operation = Sum(vec![
+            Multiply(vec![Value(2.0), Text("x".to_string())]),
+            Multiply(vec![Value(2.0), Text("x".to_string())]),
+            Multiply(vec![Value(2.0), Text("x".to_string())]),
+            Text("x".to_string()),
+        ]);
+        assert_eq!(
+            a.common_subexpressions(),
+            vec![(Multiply(vec![Value(2.0), Text("x".to_string())]), 3)]
+        );
+
+        let a: Operation = Sum(vec![Text("x".to_string()), Text("x".to_string())]);
+        assert!(a.common_subexpressions().is_empty());
+    }
+
+    #[test]
+    fn test_identity_simplification() {
+        let x = || Some(Box::new(Text("x".to_string())));
+        let value = |a: f64| Some(Box::new(Value(a)));
+
+        assert_eq!(Power(x(), value(0.0)).simplify(), Some(Value(1.0)));
+        assert_eq!(
+            Power(x(), value(1.0)).simplify(),
+            Some(Text("x".to_string()))
+        );
+        assert_eq!(Power(value(0.0), value(2.0)).simplify(), Some(Value(0.0)));
+        assert_ne!(Power(value(0.0), x()).simplify(), Some(Value(0.0)));
+        let negative: Operation = Power(value(0.0), Some(Box::new(Negate(x()))));
+        assert_ne!(negative.simplify(), Some(Value(0.0)));
+        assert!(!Power(value(0.0), value(-2.0))
+            .simplify()
+            .is_some_and(|x| x.is_zero()));
+        assert_eq!(Power(value(1.0), x()).simplify(), Some(Value(1.0)));
+        assert_eq!(
+            Power(x(), Some(Box::new(Sum(vec![Value(0.5), Value(0.5)])))).simplify(),
+            Some(Text("x".to_string()))
+        );
+
+        assert_eq!(
+            Divide(x(), value(1.0)).simplify(),
+            Some(Text("x".to_string()))
+        );
+        assert_eq!(Divide(value(0.0), x()).simplify(), Some(Value(0.0)));
+        assert_eq!(
+            Divide(Some(Box::new(Multiply(vec![Value(0.0), Value(5.0)]))), x()).simplify(),
+            Some(Value(0.0))
+        );
+        assert!(Divide(value(0.0), value(0.0))
+            .simplify()
+            .unwrap()
+            .value()
+            .is_nan());
+    }
+
+    #[test]
+    fn test_unordered_equality() {
+        let x = || Text("x".to_string());
+        let y = || Text("y".to_string());
+        assert_eq!(Sum(vec![x(), y()]), Sum(vec![y(), x()]));
+        assert_ne!(Sum(vec![x(), x()]), Sum(vec![x(), y()]));
+        assert_ne!(Multiply(vec![x(), y()]), Multiply(vec![y(), y()]));
+    }
+
+    #[test]
+    fn test_expand_powers() {
+        let x = || Text("x".to_string());
+        let binomial = |n: f64| {
+            Power(
+                Some(Box::new(Sum(vec![x(), Value(1.0)]))),
+                Some(Box::new(Value(n))),
+            )
+        };
+        let power = |n: f64| Power(Some(Box::new(x())), Some(Box::new(Value(n))));
+
+        assert_eq!(
+            binomial(2.0).expand_powers(8),
+            Sum(vec![
+                power(2.0),
+                Multiply(vec![Value(2.0), x()]),
+                Value(1.0)
+            ])
+        );
+        assert_eq!(
+            binomial(2.0).expand_powers(8).equation_repr(),
+            "x^2 + 2 * x + 1"
+        );
+        assert_eq!(
+            binomial(3.0).expand_powers(8),
+            Sum(vec![
+                power(3.0),
+                Multiply(vec![Value(3.0), power(2.0)]),
+                Multiply(vec![Value(3.0), x()]),
+                Value(1.0)
+            ])
+        );
+
+        // (x - y)^2 = x^2 - 2xy + y^2
+        let a: Operation = Power(
+            Some(Box::new(Sum(vec![
+                x(),
+                Negate(Some(Box::new(Text("y".to_string())))),
+            ]))),
+            Some(Box::new(Value(2.0))),
+        );
+        assert_eq!(a.expand_powers(8).equation_repr(), "x^2 + -2 * x * y + y^2");
+
+        // Exponents above the cap are left alone
+        assert_eq!(binomial(3.0).expand_powers(2), binomial(3.0));
+        // Nested powers are expanded too
+        let a: Operation = Multiply(vec![Value(2.0), binomial(2.0)]);
+        assert_eq!(
+            a.expand_powers(8),
+            Multiply(vec![Value(2.0), binomial(2.0).expand_powers(8)])
+        );
+    }
+
+    #[test]
+    fn test_non_finite_values() {
+        let value = |a: f64| Some(Box::new(Value(a)));
+
+        assert_eq!(Value(f64::NAN), Value(f64::NAN));
+        assert_ne!(Value(f64::NAN), Value(1.0));
+        assert!(Value(1.0).is_finite());
+        assert!(!Sum(vec![Text("x".to_string()), Value(f64::INFINITY)]).is_finite());
+
+        // Division by zero is left symbolic
+        let a: Operation = Divide(value(1.0), value(0.0));
+        assert_eq!(a.simplify(), Some(Divide(value(1.0), value(0.0))));
+        assert!(a.simplify().unwrap().value().is_infinite());
+
+        // Overflowing powers are left symbolic
+        let a: Operation = Power(value(10.0), value(400.0));
+        assert_eq!(a.simplify(), None);
+
+        // Non-finite values are not folded into the constant
+        let a: Operation = Sum(vec![Value(1.0), Value(f64::NAN), Value(2.0)]);
+        assert_eq!(a.simplify(), Some(Sum(vec![Value(f64::NAN), Value(3.0)])));
+        let a: Operation = Multiply(vec![Value(2.0), Value(f64::INFINITY), Value(3.0)]);
+        assert_eq!(
+            a.simplify(),
+            Some(Multiply(vec![Value(f64::INFINITY), Value(6.0)]))
+        );
+
+        // Folding that would overflow keeps the values apart
+        let x = || Text("x".to_string());
+        let a: Operation = Multiply(vec![Value(1e200), Value(1e200), x()]);
+        assert_eq!(a.simplify(), Some(a.clone()));
+        assert!(a.simplify_fully().is_finite());
+        let a: Operation = Sum(vec![Value(1e308), Value(1e308), x()]);
+        assert_eq!(a.simplify(), Some(a.clone()));
+        let a: Operation = Multiply(vec![Value(1e200), Value(1e200), Value(0.0), x()]);
+        assert_eq!(a.simplify(), Some(Value(0.0)));
+
+        // A sum with a symbolic child is not folded into a number
+        let a: Operation = Sum(vec![
+            Multiply(vec![Value(2.0), Text("x".to_string())]),
+            Value(3.0),
+        ]);
+        assert_eq!(
+            a.simplify(),
+            Some(Sum(vec![
+                Multiply(vec![Text("x".to_string()), Value(2.0)]),
+                Value(3.0)
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_compile() {
+        let build = |x: Operation, y: Operation| {
+            Sum(vec![
+                Multiply(vec![
+                    Value(3.0),
+                    Power(Some(Box::new(x.clone())), Some(Box::new(Value(2.0)))),
+                ]),
+                Divide(Some(Box::new(y)), Some(Box::new(Sum(vec![x, Value(1.0)])))),
+                Negate(Some(Box::new(Operation::rational(1, 4)))),
+            ])
+        };
+        let vars: Vec<String> = vec!["x".to_string(), "y".to_string()];
+        let compiled = build(Text("x".to_string()), Operation::variable("y"))
+            .compile(&vars)
+            .unwrap();
+        for (x, y) in [(0.0, 1.0), (2.0, -3.0), (0.5, 4.0), (-7.0, 2.5)] {
+            let bound: Operation = build(
+                Operation::variable_with_value("x", x),
+                Operation::variable_with_value("y", y),
+            );
+            assert_eq!(compiled(&[x, y]), bound.value());
+        }
+
+        // Symbols must be listed or carry a value
+        let a: Operation = Sum(vec![Text("x".to_string()), Text("z".to_string())]);
+        assert!(a.compile(&vars).is_err());
+        let a: Operation = Sum(vec![
+            Text("x".to_string()),
+            Operation::variable_with_value("z", 2.0),
+        ]);
+        assert_eq!(a.compile(&vars).unwrap()(&[1.0, 0.0]), 3.0);
+
+        // Values missing from a short slice are NaN
+        let a: Operation = Sum(vec![Text("x".to_string()), Text("y".to_string())]);
+        let compiled = a.compile(&vars).unwrap();
+        assert!(compiled(&[1.0]).is_nan());
+        assert!(compiled(&[]).is_nan());
+    }
+
+    #[test]
+    fn test_evaluate_grid() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let x = || Text("x".to_string());
+        // x^2 - 1
+        let a: Operation = Sum(vec![
+            Power(boxed(x()), boxed(Value(2.0))),
+            Negate(boxed(Value(1.0))),
+        ]);
+        assert_eq!(
+            a.evaluate_grid("x", -2.0, 2.0, 5),
+            vec![
+                (-2.0, 3.0),
+                (-1.0, 0.0),
+                (0.0, -1.0),
+                (1.0, 0.0),
+                (2.0, 3.0)
+            ]
+        );
+        assert_eq!(a.evaluate_grid("x", 3.0, 5.0, 1), vec![(3.0, 8.0)]);
+        assert!(a.evaluate_grid("x", 0.0, 1.0, 0).is_empty());
+
+        // x^0.5 is NaN for negative x
+        let root: Operation = Power(boxed(x()), boxed(Value(0.5)));
+        let points: Vec<(f64, f64)> = root.evaluate_grid("x", -1.0, 1.0, 3);
+        assert!(points[0].1.is_nan());
+        assert_eq!(points[1..], [(0.0, 0.0), (1.0, 1.0)]);
+        let unbound: Operation = Sum(vec![x(), Text("z".to_string())]);
+        assert!(unbound
+            .evaluate_grid("x", 0.0, 1.0, 2)
+            .iter()
+            .all(|(_, y)| y.is_nan()));
+    }
+
+    #[test]
+    fn test_path_access() {
+        let mut a: Operation = Divide(
+            Some(Box::new(Sum(vec![
+                Text("x".to_string()),
+                Negate(Some(Box::new(Text("y".to_string())))),
+            ]))),
+            Some(Box::new(Value(2.0))),
+        );
+        assert_eq!(a.path_get(&[]), Some(&a.clone()));
+        assert_eq!(a.path_get(&[0, 0]), Some(&Text("x".to_string())));
+        assert_eq!(a.path_get(&[0, 1, 0]), Some(&Text("y".to_string())));
+        assert_eq!(a.path_get(&[1]), Some(&Value(2.0)));
+        assert_eq!(a.path_get(&[0, 2]), None);
+        assert_eq!(a.path_get(&[1, 0]), None);
+
+        assert!(a.path_set(&[0, 1, 0], Value(3.0)));
+        assert!(!a.path_set(&[2], Value(3.0)));
+        assert_eq!(
+            a,
+            Divide(
+                Some(Box::new(Sum(vec![
+                    Text("x".to_string()),
+                    Negate(Some(Box::new(Value(3.0)))),
+                ]))),
+                Some(Box::new(Value(2.0))),
+            )
+        );
+    }
+
+    #[test]
+    fn test_factor() {
+        let x = || Text("x".to_string());
+        let y = || Text("y".to_string());
+        let a = || Text("a".to_string());
+
+        let op: Operation = Sum(vec![
+            Multiply(vec![Value(2.0), x()]),
+            Multiply(vec![Value(2.0), y()]),
+        ]);
+        assert_eq!(op.factor(), Multiply(vec![Value(2.0), Sum(vec![x(), y()])]));
+
+        let op: Operation = Sum(vec![Multiply(vec![a(), x()]), Multiply(vec![a(), y()])]);
+        assert_eq!(op.factor(), Multiply(vec![a(), Sum(vec![x(), y()])]));
+
+        // 6x^2 + 4x = 2x(3x + 2)
+        let op: Operation = Sum(vec![
+            Multiply(vec![
+                Value(6.0),
+                Power(Some(Box::new(x())), Some(Box::new(Value(2.0)))),
+            ]),
+            Multiply(vec![Value(4.0), x()]),
+        ]);
+        assert_eq!(
+            op.factor(),
+            Multiply(vec![
+                Value(2.0),
+                x(),
+                Sum(vec![Multiply(vec![Value(3.0), x()]), Value(2.0)])
+            ])
+        );
+
+        // Nothing in common
+        let op: Operation = Sum(vec![Multiply(vec![Value(2.0), x()]), y()]);
+        assert_eq!(op.factor(), op);
+    }
+
+    #[test]
+    fn test_negative_exponent_simplification() {
+        let x = || Box::new(Text("x".to_string()));
+
+        let a: Operation = Power(Some(x()), Some(Box::new(Value(-1.0))));
+        assert_eq!(
+            a.simplify(),
+            Some(Divide(Some(Box::new(Value(1.0))), Some(x())))
+        );
+
+        let a: Operation = Power(
+            Some(x()),
+            Some(Box::new(Negate(Some(Box::new(Value(2.0)))))),
+        );
+        assert_eq!(
+            a.simplify(),
+            Some(Divide(
+                Some(Box::new(Value(1.0))),
+                Some(Box::new(Power(Some(x()), Some(Box::new(Value(2.0))))))
+            ))
+        );
+
+        // Numeric bases still fold and fractional exponents are kept
+        let a: Operation = Power(Some(Box::new(Value(2.0))), Some(Box::new(Value(-1.0))));
+        assert_eq!(a.simplify(), Some(Value(0.5)));
+        let a: Operation = Power(Some(x()), Some(Box::new(Value(-0.5))));
+        assert_eq!(a.simplify(), None);
+    }
+
+    #[test]
+    fn test_sexpr() {
+        let a: Operation = Divide(
+            Some(Box::new(Sum(vec![
+                Text("x".to_string()),
+                Text("y".to_string()),
+            ]))),
+            Some(Box::new(Text("z".to_string()))),
+        );
+        assert_eq!(a.to_sexpr(), "(/ (+ x y) z)");
+        assert_eq!(Operation::from_sexpr("(/ (+ x y) z)"), Ok(a));
+
+        let a: Operation = Equal(
+            Some(Box::new(Multiply(vec![
+                Value(-2.5),
+                Power(
+                    Some(Box::new(Text("R 1".to_string()))),
+                    Some(Box::new(Negate(Some(Box::new(Value(2.0)))))),
+                ),
+                Rational(1, 3),
+                Mapping(4),
+                Text("12".to_string()),
+            ]))),
+            Some(Box::new(Divide(Some(Box::new(Sum(vec![]))), None))),
+        );
+        let sexpr: String = a.to_sexpr();
+        assert_eq!(
+            sexpr,
+            "(= (* -2.5 (^ \"R 1\" (- 2)) (rational 1 3) (map 4) \"12\") (/ (+) nil))"
+        );
+        let b: Operation = Operation::from_sexpr(&sexpr).unwrap();
+        assert_eq!(b.to_sexpr(), sexpr);
+
+        assert!(Operation::from_sexpr("(/ x)").is_err());
+        assert!(Operation::from_sexpr("(+ x y").is_err());
+        assert!(Operation::from_sexpr("x y").is_err());
+        assert!(Operation::from_sexpr("nil").is_err());
+
+        // Fractions are reduced and indices must be non negative
+        assert_eq!(
+            Operation::from_sexpr("(rational 2 -4)"),
+            Ok(Operation::rational(-1, 2))
+        );
+        assert!(Operation::from_sexpr("(rational 1 0)").is_err());
+        assert!(Operation::from_sexpr("(map -1)").is_err());
+
+        // Quotes and backslashes in names round trip
+        let a: Operation = Text("a\"b\\c".to_string());
+        assert_eq!(a.to_sexpr(), "\"a\\\"b\\\\c\"");
+        assert_eq!(Operation::from_sexpr(&a.to_sexpr()), Ok(a));
+    }
+
+    #[test]
+    fn test_cleanup() {
+        let x = || Text("x".to_string());
+        let y = || Text("y".to_string());
+
+        let mut a: Operation = Sum(vec![x()]);
+        a.cleanup();
+        assert_eq!(a, x());
+
+        let mut a: Operation = Multiply(vec![Multiply(vec![x()])]);
+        a.cleanup();
+        assert_eq!(a, x());
+
+        let mut a: Operation = Sum(vec![x(), Value(0.0), Multiply(vec![Value(1.0), y()])]);
+        a.cleanup();
+        assert_eq!(a, Sum(vec![x(), y()]));
+
+        let mut a: Operation = Divide(
+            Some(Box::new(Multiply(vec![x(), Value(1.0)]))),
+            Some(Box::new(Equal(
+                Some(Box::new(Sum(vec![Value(0.0), y()]))),
+                Some(Box::new(Negate(Some(Box::new(Negate(Some(
+                    Box::new(x()),
+                ))))))),
+            ))),
+        );
+        a.cleanup();
+        assert_eq!(
+            a.equation_repr(),
+            Divide(
+                Some(Box::new(x())),
+                Some(Box::new(Equal(Some(Box::new(y())), Some(Box::new(x())))))
+            )
+            .equation_repr()
+        );
+
+        let mut a: Operation = Sum(vec![Value(0.0), Value(0.0)]);
+        a.cleanup();
+        assert_eq!(a, Value(0.0));
+        let mut a: Operation = Multiply(vec![Value(1.0)]);
+        a.cleanup();
+        assert_eq!(a, Value(1.0));
+    }
+
+    #[test]
+    fn test_derivative() {
+        let x = || Text("x".to_string());
+        let at =
+            |op: &Operation, x: f64| op.evaluate(&HashMap::from([("x".to_string(), x)])).unwrap();
+
+        // d/dx 3x^2 + 2x + 1 = 6x + 2
+        let a: Operation = Sum(vec![
+            Multiply(vec![
+                Value(3.0),
+                Power(Some(Box::new(x())), Some(Box::new(Value(2.0)))),
+            ]),
+            Multiply(vec![Value(2.0), x()]),
+            Value(1.0),
+        ]);
+        let d: Operation = a.derivative("x").unwrap();
+        assert_eq!(at(&d, 0.0), 2.0);
+        assert_eq!(at(&d, 2.0), 14.0);
+        assert_eq!(at(&a.derivative("y").unwrap(), 2.0), 0.0);
+
+        // d/dx 1/x = -1/x^2
+        let a: Operation = Divide(Some(Box::new(Value(1.0))), Some(Box::new(x())));
+        assert_eq!(at(&a.derivative("x").unwrap(), 2.0), -0.25);
+
+        // d/dx 2^x = 2^x ln(2)
+        let a: Operation = Power(Some(Box::new(Value(2.0))), Some(Box::new(x())));
+        assert_eq!(at(&a.derivative("x").unwrap(), 3.0), 8.0 * 2f64.ln());
+
+        // x^x needs a logarithm of a symbol
+        let a: Operation = Power(Some(Box::new(x())), Some(Box::new(x())));
+        assert_eq!(a.derivative("x"), None);
+    }
+
+    #[test]
+    fn test_multiply_reciprocal() {
+        let reciprocal = |x: &str| {
+            Divide(
+                Some(Box::new(Value(1.0))),
+                Some(Box::new(Text(x.to_string()))),
+            )
+        };
+        let a: Operation = Multiply(vec![Text("x".to_string()), reciprocal("y")]);
+        assert_eq!(
+            a.simplify(),
+            Some(Divide(
+                Some(Box::new(Text("x".to_string()))),
+                Some(Box::new(Text("y".to_string())))
+            ))
+        );
+
+        let a: Operation = Multiply(vec![
+            reciprocal("y"),
+            Text("x".to_string()),
+            Value(2.0),
+            reciprocal("z"),
+        ]);
+        assert_eq!(
+            a.simplify(),
+            Some(Divide(
+                Some(Box::new(Multiply(vec![Text("x".to_string()), Value(2.0)]))),
+                Some(Box::new(Multiply(vec![
+                    Text("y".to_string()),
+                    Text("z".to_string())
+                ])))
+            ))
+        );
+
+        // The folded products are simplified
+        let a: Operation = Multiply(vec![Value(2.0), Value(3.0), reciprocal("y")]);
+        assert_eq!(
+            a.simplify(),
+            Some(Divide(
+                Some(Box::new(Value(6.0))),
+                Some(Box::new(Text("y".to_string())))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_decompose_term() {
+        let x = || Text("x".to_string());
+        let y = || Text("y".to_string());
+        assert_eq!(
+            Multiply(vec![Value(3.0), x(), y()]).decompose_term(),
+            (3.0, Multiply(vec![x(), y()]))
+        );
+        assert_eq!(
+            Negate(Some(Box::new(Multiply(vec![Value(2.0), x()])))).decompose_term(),
+            (-2.0, x())
+        );
+        assert_eq!(Value(4.0).decompose_term(), (4.0, Value(1.0)));
+        assert_eq!(x().decompose_term(), (1.0, x()));
+        assert_eq!(
+            Multiply(vec![Value(2.0), Negate(Some(Box::new(x()))), Value(5.0)]).decompose_term(),
+            (-10.0, x())
+        );
+    }
+
+    #[test]
+    fn test_latex_diff() {
+        let previous: Operation = Sum(vec![
+            Text("x".to_string()),
+            Multiply(vec![Value(2.0), Value(3.0)]),
+        ]);
+        let current: Operation = Sum(vec![Text("x".to_string()), Value(6.0)]);
+        assert_eq!(current.latex_diff(&previous), "{$x$ + \\color{red}{6}}");
+        assert_eq!(current.latex_diff(&current), current.latex_string());
+
+        let previous: Operation = Divide(
+            Some(Box::new(Text("x".to_string()))),
+            Some(Box::new(Text("y".to_string()))),
+        );
+        let current: Operation = Divide(
+            Some(Box::new(Text("x".to_string()))),
+            Some(Box::new(Text("z".to_string()))),
+        );
+        assert_eq!(
+            current.latex_diff(&previous),
+            "\\frac{$x$}{\\color{red}{$z$}}"
+        );
+
+        // Subtrees without a value are still highlighted
+        let x = || Text("x".to_string());
+        let equal: Operation = Sum(vec![Equal(Some(Box::new(x())), Some(Box::new(Value(1.0))))]);
+        assert!(equal
+            .latex_diff(&Sum(vec![Value(1.0)]))
+            .contains("\\color{red}"));
+        let current: Operation = Sum(vec![Mapping(0), x()]);
+        let previous: Operation = Sum(vec![Mapping(1), x()]);
+        assert!(current.latex_diff(&previous).contains("\\color{red}"));
+    }
+
+    #[test]
+    fn test_empty_collections() {
+        assert_eq!(Sum(vec![]).simplify(), Some(Value(0.0)));
+        assert_eq!(Multiply(vec![]).simplify(), Some(Value(1.0)));
+        assert_eq!(Sum(vec![]).equation_repr(), "0");
+        assert_eq!(Multiply(vec![]).equation_repr(), "1");
+        assert_eq!(Sum(vec![]).latex_string(), "0");
+        assert_eq!(Multiply(vec![]).latex_string(), "1");
+        assert_eq!(Sum(vec![]).value(), 0.0);
+        assert_eq!(Multiply(vec![]).value(), 1.0);
+
+        let a: Operation = Sum(vec![Value(2.0), Negate(Some(Box::new(Value(2.0))))]);
+        assert_eq!(a.simplify(), Some(Value(0.0)));
+    }
+
+    #[test]
+    fn test_multiply_coefficient_first() {
+        let a: Operation = Multiply(vec![Text("x".to_string()), Value(2.0), Value(3.0)]);
+        let simplified: Operation = a.simplify().unwrap();
+        assert_eq!(
+            simplified,
+            Multiply(vec![Value(6.0), Text("x".to_string())])
+        );
+        assert_eq!(simplified.equation_repr(), "6 * x");
+
+        let a: Operation = Multiply(vec![
+            Text("x".to_string()),
+            Value(0.5),
+            Text("y".to_string()),
+            Value(2.0),
+        ]);
+        assert_eq!(a.simplify().unwrap().equation_repr(), "x * y");
+
+        let a: Operation = Multiply(vec![Value(2.0), Value(0.5)]);
+        assert_eq!(a.simplify(), Some(Value(1.0)));
+    }
+
+    #[test]
+    fn test_flatten_associative() {
+        let t = |x: &str| Text(x.to_string());
+        // ((a + b) + c) + d
+        let a: Operation = Sum(vec![Sum(vec![Sum(vec![t("a"), t("b")]), t("c")]), t("d")]);
+        let flat: Operation = a.flatten_associative();
+        assert_eq!(flat, Sum(vec![t("a"), t("b"), t("c"), t("d")]));
+        assert_eq!(flat.equation_repr(), "a + b + c + d");
+
+        // Products inside a sum are flattened separately
+        let a: Operation = Sum(vec![
+            Multiply(vec![Multiply(vec![t("a"), t("b")]), t("c")]),
+            Sum(vec![t("d")]),
+        ]);
+        assert_eq!(
+            a.flatten_associative(),
+            Sum(vec![Multiply(vec![t("a"), t("b"), t("c")]), t("d")])
+        );
+    }
+
+    #[test]
+    fn test_node_count() {
+        assert_eq!(Value(1.0).node_count(), 1);
+        let a: Operation = Divide(
+            Some(Box::new(Sum(vec![
+                Text("x".to_string()),
+                Negate(Some(Box::new(Text("y".to_string())))),
+            ]))),
+            Some(Box::new(Value(2.0))),
+        );
+        assert_eq!(a.node_count(), 6);
+    }
+
+    #[test]
+    fn test_simplify_divide_to_reciprocal() {
+        let options = SimplifyOptions {
+            divide_to_reciprocal: true,
+            ..Default::default()
+        };
+        let a: Operation = Divide(
+            Some(Box::new(Multiply(vec![Value(2.0), Text("x".to_string())]))),
+            Some(Box::new(Value(4.0))),
+        );
+        let b: Operation = a.simplify_with_options(options);
+        assert_eq!(b, Multiply(vec![Value(0.5), Text("x".to_string())]));
+        assert_eq!(b.equation_repr(), "0.5 * x");
+
+        // The fraction is preserved by default
+        assert_eq!(a.simplify_with_options(SimplifyOptions::default()), a);
+
+        // Division by zero is left alone
+        let a: Operation = Divide(
+            Some(Box::new(Text("x".to_string()))),
+            Some(Box::new(Value(0.0))),
+        );
+        assert_eq!(a.simplify_with_options(options), a);
+
+        // Without a division the option leaves the operation to the usual simplification
+        let nested: Operation = Multiply(vec![
+            Text("x".to_string()),
+            Sum(vec![Text("y".to_string()), Text("z".to_string())]),
+        ]);
+        assert_eq!(
+            nested.simplify_with_options(options),
+            nested.simplify_with_options(SimplifyOptions::default())
+        );
+    }
+
+    #[test]
+    fn test_negate_divide() {
+        let a: Operation = Negate(Some(Box::new(Divide(
+            Some(Box::new(Value(6.0))),
+            Some(Box::new(Value(3.0))),
+        ))));
+        assert_eq!(a.simplify(), Some(Value(-2.0)));
+
+        let a: Operation = Negate(Some(Box::new(Divide(
+            Some(Box::new(Text("x".to_string()))),
+            Some(Box::new(Text("y".to_string()))),
+        ))));
+        assert_eq!(
+            a.simplify(),
+            Some(Divide(
+                Some(Box::new(Negate(Some(Box::new(Text("x".to_string())))))),
+                Some(Box::new(Text("y".to_string())))
+            ))
+        );
+
+        let a: Operation = Negate(Some(Box::new(Divide(
+            Some(Box::new(Multiply(vec![Value(2.0), Text("x".to_string())]))),
+            Some(Box::new(Text("y".to_string()))),
+        ))));
+        assert_eq!(a.simplify().unwrap().value(), -2.0);
+    }
+
+    #[test]
+    fn test_precedence() {
+        let x = || Some(Box::new(Text("x".to_string())));
+        let power_of = |a: Operation| Power(Some(Box::new(a)), Some(Box::new(Value(2.0))));
+        let equal: Operation = Equal(x(), x());
+        let sum: Operation = Sum(vec![]);
+        let multiply: Operation = Multiply(vec![]);
+        let divide: Operation = Divide(x(), x());
+        let negate: Operation = Negate(x());
+        let power: Operation = Power(x(), x());
+        let atom: Operation = Text("x".to_string());
+
+        assert!(equal.precedence() < sum.precedence());
+        assert!(sum.precedence() < multiply.precedence());
+        assert_eq!(multiply.precedence(), divide.precedence());
+        assert_eq!(divide.precedence(), Rational(1, 2).precedence());
+        assert!(divide.precedence() < negate.precedence());
+        assert!(negate.precedence() < power.precedence());
+        assert!(power.precedence() < atom.precedence());
+        assert_eq!(atom.precedence(), Value(1.0).precedence());
+        assert_eq!(atom.precedence(), Mapping(0).precedence());
+
+        // Children binding looser than their parent are grouped
+        let t = |x: &str| Text(x.to_string());
+        let sum = || Sum(vec![t("x"), Value(1.0)]);
+        assert_eq!(
+            Multiply(vec![sum(), Value(2.0)]).equation_repr(),
+            "{x + 1} * 2"
+        );
+        assert_eq!(Negate(Some(Box::new(sum()))).equation_repr(), "-{x + 1}");
+        assert_eq!(power_of(sum()).equation_repr(), "{x + 1}^2");
+        assert_eq!(power_of(power_of(t("x"))).equation_repr(), "{x^2}^2");
+        assert_eq!(power_of(t("x")).equation_repr(), "x^2");
+        assert_eq!(
+            Multiply(vec![sum(), Value(2.0)]).latex_string(),
+            "({$x$ + 1}) \\cdot 2"
+        );
+        assert_eq!(Negate(Some(Box::new(sum()))).latex_string(), "-({$x$ + 1})");
+    }
+
+    #[test]
+    fn test_simplify_mode() {
+        let fraction = |a: f64, b: f64| Divide(Some(Box::new(Value(a))), Some(Box::new(Value(b))));
+        assert_eq!(
+            fraction(2.0, 3.0).simplify_with(SimplifyMode::ExactOnly),
+            Some(fraction(2.0, 3.0))
+        );
+        assert_eq!(
+            fraction(6.0, 3.0).simplify_with(SimplifyMode::ExactOnly),
+            Some(Value(2.0))
+        );
+        assert_eq!(
+            fraction(2.0, 3.0).simplify_with(SimplifyMode::Numeric),
+            Some(Value(2.0 / 3.0))
+        );
+        assert_eq!(fraction(2.0, 3.0).simplify(), Some(Value(2.0 / 3.0)));
+
+        // The mode applies to nested divisions as well
+        let a: Operation = Sum(vec![fraction(2.0, 3.0), fraction(4.0, 2.0)]);
+        assert_eq!(
+            a.simplify_with(SimplifyMode::ExactOnly),
+            Some(Sum(vec![fraction(2.0, 3.0), Value(2.0)]))
+        );
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn test_send_between_threads() {
+        let a: Operation = Sum(vec![
+            Operation::variable_with_value("x", 2.0),
+            Multiply(vec![Value(3.0), Text("y".to_string())]),
+        ]);
+        let handle = std::thread::spawn(move || a.simplify().map(|x| x.equation_repr()));
+        assert_eq!(handle.join().unwrap(), Some("x + 3 * y".to_string()));
+    }
+
+    #[test]
+    fn test_denominators() {
+        let over =
+            |a: Operation, b: &str| Divide(Some(Box::new(a)), Some(Box::new(Text(b.to_string()))));
+        let a: Operation = Sum(vec![
+            over(Value(1.0), "x"),
+            Multiply(vec![Value(2.0), over(Text("z".to_string()), "y")]),
+            over(over(Value(3.0), "x"), "y"),
+        ]);
+        assert_eq!(
+            a.denominators(),
+            vec![Text("x".to_string()), Text("y".to_string())]
+        );
+        assert!(Text("x".to_string()).denominators().is_empty());
+    }
+
+    #[test]
+    fn test_divide_reduce_coefficients() {
+        let x = || Text("x".to_string());
+        let y = || Text("y".to_string());
+        let a: Operation = Divide(
+            Some(Box::new(Multiply(vec![Value(6.0), x()]))),
+            Some(Box::new(Multiply(vec![Value(2.0), y()]))),
+        );
+        assert_eq!(
+            a.simplify(),
+            Some(Divide(
+                Some(Box::new(Multiply(vec![Value(3.0), x()]))),
+                Some(Box::new(y()))
+            ))
+        );
+
+        // The simplified operands are reduced
+        let a: Operation = Divide(
+            Some(Box::new(Multiply(vec![
+                Value(6.0),
+                Sum(vec![x(), Value(0.0)]),
+            ]))),
+            Some(Box::new(Multiply(vec![Value(2.0), y()]))),
+        );
+        assert_eq!(
+            a.simplify(),
+            Some(Divide(
+                Some(Box::new(Multiply(vec![Value(3.0), x()]))),
+                Some(Box::new(y()))
+            ))
+        );
+
+        // Coefficients that cancel completely leave the bare remainder
+        let a: Operation = Divide(
+            Some(Box::new(Multiply(vec![Value(2.0), x(), y()]))),
+            Some(Box::new(Negate(Some(Box::new(Multiply(vec![
+                Value(-2.0),
+                y(),
+            ])))))),
+        );
+        assert_eq!(
+            a.simplify(),
+            Some(Divide(
+                Some(Box::new(Multiply(vec![x(), y()]))),
+                Some(Box::new(y()))
+            ))
+        );
+
+        let a: Operation = Divide(
+            Some(Box::new(Value(3.0))),
+            Some(Box::new(Multiply(vec![Value(4.0), y()]))),
+        );
+        assert_eq!(
+            a.simplify(),
+            Some(Divide(Some(Box::new(Value(0.75))), Some(Box::new(y()))))
+        );
+        assert_eq!(
+            a.simplify_with(SimplifyMode::ExactOnly),
+            Some(Divide(
+                Some(Box::new(Value(3.0))),
+                Some(Box::new(Multiply(vec![Value(4.0), y()])))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_alpha_eq() {
+        let t = |x: &str| Text(x.to_string());
+        assert!(Sum(vec![t("a"), t("b")]).alpha_eq(&Sum(vec![t("x"), t("y")])));
+        assert!(!Sum(vec![t("a"), t("a")]).alpha_eq(&Sum(vec![t("x"), t("y")])));
+        assert!(!Sum(vec![t("a"), t("b")]).alpha_eq(&Sum(vec![t("x"), t("x")])));
+
+        let a: Operation = Divide(
+            Some(Box::new(Multiply(vec![Value(2.0), t("a")]))),
+            Some(Box::new(Sum(vec![t("a"), Operation::variable("b")]))),
+        );
+        let b: Operation = Divide(
+            Some(Box::new(Multiply(vec![Value(2.0), t("y")]))),
+            Some(Box::new(Sum(vec![t("y"), t("z")]))),
+        );
+        assert!(a.alpha_eq(&b));
+
+        // Constants are not renamed
+        let c: Operation = Divide(
+            Some(Box::new(Multiply(vec![Value(3.0), t("y")]))),
+            Some(Box::new(Sum(vec![t("y"), t("z")]))),
+        );
+        assert!(!a.alpha_eq(&c));
+        assert!(!Sum(vec![t("a")]).alpha_eq(&Multiply(vec![t("a")])));
+    }
+
+    #[test]
+    fn test_equation_from_strings() {
+        let a: Operation = Operation::equation_from_strings("(+ a b)", "c").unwrap();
+        assert_eq!(a.to_sexpr(), "(= (+ a b) c)");
+        assert_eq!(a.equation_repr(), "a + b = c");
+        assert!(Operation::equation_from_strings("(+ a b", "c").is_err());
+        assert!(Operation::equation_from_strings("a", ")").is_err());
+    }
+
+    #[test]
+    fn test_sum_cancel_inverses() {
+        let x = || Text("x".to_string());
+        let a: Operation = Sum(vec![x(), Negate(Some(Box::new(x())))]);
+        assert_eq!(a.simplify(), Some(Value(0.0)));
+
+        let a: Operation = Sum(vec![
+            Multiply(vec![Value(2.0), x()]),
+            Text("y".to_string()),
+            Negate(Some(Box::new(Multiply(vec![Value(2.0), x()])))),
+        ]);
+        assert_eq!(a.simplify(), Some(Text("y".to_string())));
+
+        let a: Operation = Sum(vec![
+            Multiply(vec![Value(-3.0), x()]),
+            Multiply(vec![x(), Value(3.0)]),
+            Value(1.0),
+        ]);
+        assert_eq!(a.simplify(), Some(Value(1.0)));
+
+        // Different bases or coefficients are kept
+        let a: Operation = Sum(vec![
+            Multiply(vec![Value(2.0), x()]),
+            Negate(Some(Box::new(x()))),
+            Negate(Some(Box::new(Text("y".to_string())))),
+        ]);
+        assert_eq!(a.simplify(), Some(a));
+
+        // Infinities do not cancel to zero
+        let a: Operation = Sum(vec![Value(f64::INFINITY), Value(f64::NEG_INFINITY)]);
+        assert!(a.simplify().unwrap().value().is_nan());
+        let a: Operation = Sum(vec![
+            Multiply(vec![Value(f64::INFINITY), x()]),
+            Multiply(vec![Value(f64::NEG_INFINITY), x()]),
+        ]);
+        assert_ne!(a.simplify(), Some(Value(0.0)));
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let x = || Text("x".to_string());
+        let a: Operation = Sum(vec![
+            Multiply(vec![Value(2.0), x()]),
+            Multiply(vec![Value(2.0), x()]),
+        ]);
+        let dot: String = a.to_dot();
+        assert!(dot.starts_with("digraph {\n"));
+        assert!(dot.ends_with('}'));
+        assert_eq!(dot.matches("[label=").count(), 7);
+        assert_eq!(dot.matches(" -> ").count(), 6);
+        assert!(dot.contains("    n0 [label=\"Sum\"];\n"));
+        assert!(dot.contains("    n0 -> n4;\n"));
+        assert!(dot.contains("    n6 [label=\"x\"];\n"));
+
+        let dot: String = Text("say \"hi\"".to_string()).to_dot();
+        assert_eq!(dot, "digraph {\n    n0 [label=\"say \\\"hi\\\"\"];\n}");
+    }
+
+    #[test]
+    fn test_check_derivative() {
+        let at: HashMap<String, f64> = HashMap::from([("x".to_string(), 3.0)]);
+        let a: Operation = Power(
+            Some(Box::new(Text("x".to_string()))),
+            Some(Box::new(Value(2.0))),
+        );
+        assert!(a.check_derivative("x", &at, 1e-4, 1e-6));
+
+        let a: Operation = Divide(
+            Some(Box::new(Value(1.0))),
+            Some(Box::new(Sum(vec![Text("x".to_string()), Value(1.0)]))),
+        );
+        assert!(a.check_derivative("x", &at, 1e-4, 1e-6));
+
+        // Unbound symbols cannot be evaluated
+        let a: Operation = Multiply(vec![Text("x".to_string()), Text("y".to_string())]);
+        assert!(!a.check_derivative("x", &at, 1e-4, 1e-6));
+    }
+
+    #[test]
+    fn test_simplify_batch() {
+        let shared = || {
+            Divide(
+                Some(Box::new(Multiply(vec![Value(6.0), Text("x".to_string())]))),
+                Some(Box::new(Value(3.0))),
+            )
+        };
+        let ops: Vec<Operation> = vec![
+            Sum(vec![shared(), Value(1.0), Value(2.0)]),
+            Multiply(vec![Text("y".to_string()), shared()]),
+            Power(Some(Box::new(shared())), Some(Box::new(Value(1.0)))),
+            Text("z".to_string()),
+        ];
+        let individual: Vec<Operation> = ops
+            .iter()
+            .map(|x| x.simplify().unwrap_or_else(|| x.clone()))
+            .collect();
+        let batch: Vec<Operation> = simplify_batch(&ops);
+        assert_eq!(batch, individual);
+        assert_eq!(batch[3], Text("z".to_string()));
+        assert!(simplify_batch(&[]).is_empty());
+
+        // A cached subtree is reused inside other operations instead of being simplified again
+        let mut cache = HashMap::new();
+        let _ = shared().simplify_in(SimplifyMode::default(), Some(&mut cache));
+        let entries: usize = cache.values().map(|x| x.len()).sum();
+        let mut hasher = DefaultHasher::new();
+        shared().hash(&mut hasher);
+        cache.insert(
+            hasher.finish(),
+            vec![(shared(), Some(Text("cached".to_string())))],
+        );
+        let a: Operation = Multiply(vec![Text("y".to_string()), shared()]);
+        assert_eq!(
+            a.simplify_in(SimplifyMode::default(), Some(&mut cache)),
+            Some(Multiply(vec![
+                Text("y".to_string()),
+                Text("cached".to_string())
+            ]))
+        );
+        // Only the new product was added
+        assert_eq!(cache.values().map(|x| x.len()).sum::<usize>(), entries + 1);
+    }
+
+    #[test]
+    fn test_leaves_and_children() {
+        let x = || Text("x".to_string());
+        assert!(x().is_leaf());
+        assert!(Value(1.0).is_leaf());
+        assert!(Mapping(0).is_leaf());
+        assert!(Operation::variable("y").is_leaf());
+        assert!(!Sum(vec![]).is_leaf());
+        assert!(!Negate(None).is_leaf());
+
+        let a: Operation = Divide(Some(Box::new(x())), Some(Box::new(Value(2.0))));
+        assert_eq!(a.children(), vec![&x(), &Value(2.0)]);
+        let a: Operation = Sum(vec![x(), Value(1.0), x()]);
+        assert_eq!(a.children().len(), 3);
+        assert_eq!(Power(None, Some(Box::new(x()))).children(), vec![&x()]);
+        assert!(x().children().is_empty());
+    }
+
+    #[test]
+    fn test_simplify_with_assumptions() {
+        let x = || Text("x".to_string());
+        let mut nonzero = Assumptions::new();
+        nonzero.assume("x", Assumption::NonZero);
+        let mut positive = Assumptions::new();
+        positive.assume("x", Assumption::Positive);
+
+        // x / x
+        let a: Operation = Divide(Some(Box::new(x())), Some(Box::new(x())));
+        assert_eq!(a.simplify_with_assumptions(&Assumptions::new()), a);
+        assert_eq!(a.simplify_with_assumptions(&nonzero), Value(1.0));
+        assert_eq!(a.simplify_with_assumptions(&positive), Value(1.0));
+
+        // 2x / 2x
+        let a: Operation = Divide(
+            Some(Box::new(Multiply(vec![Value(2.0), x()]))),
+            Some(Box::new(Multiply(vec![Value(2.0), x()]))),
+        );
+        assert_eq!(a.simplify_with_assumptions(&nonzero), Value(1.0));
+
+        // sqrt(x^2)
+        let a: Operation = Power(
+            Some(Box::new(Power(
+                Some(Box::new(x())),
+                Some(Box::new(Value(2.0))),
+            ))),
+            Some(Box::new(Value(0.5))),
+        );
+        assert_eq!(a.simplify(), None);
+        assert_eq!(a.simplify_with_assumptions(&nonzero), a);
+        assert_eq!(a.simplify_with_assumptions(&positive), x());
+
+        // (x^2)^3 does not need an assumption
+        let a: Operation = Power(
+            Some(Box::new(Power(
+                Some(Box::new(x())),
+                Some(Box::new(Value(2.0))),
+            ))),
+            Some(Box::new(Value(3.0))),
+        );
+        assert_eq!(
+            a.simplify_with_assumptions(&Assumptions::new()),
+            Power(Some(Box::new(x())), Some(Box::new(Value(6.0))))
+        );
+    }
+
+    #[test]
+    fn test_add_merges_values() {
+        let a: Operation = Value(1.0) + Text("x".to_string()) + Value(2.0) + Value(3.0);
+        assert_eq!(a, Sum(vec![Value(6.0), Text("x".to_string())]));
+
+        let a: Operation = Value(1.0) + Sum(vec![Text("x".to_string()), Value(2.0)]);
+        assert_eq!(a, Sum(vec![Text("x".to_string()), Value(3.0)]));
+
+        let a: Operation = Sum(vec![Value(1.0), Text("x".to_string())])
+            + Sum(vec![Value(4.0), Text("y".to_string())]);
+        assert_eq!(
+            a,
+            Sum(vec![
+                Value(5.0),
+                Text("x".to_string()),
+                Text("y".to_string())
+            ])
+        );
+    }
+
+    #[test]
+    fn test_binary_operands() {
+        let x = || Box::new(Text("x".to_string()));
+        let two = || Box::new(Value(2.0));
+        let a: Operation = Divide(Some(x()), Some(two()));
+        assert_eq!(a.binary_operands(), Some((&*x(), &*two())));
+        let a: Operation = Equal(Some(two()), Some(x()));
+        assert_eq!(a.binary_operands(), Some((&*two(), &*x())));
+        let a: Operation = Power(Some(x()), Some(two()));
+        assert_eq!(a.binary_operands(), Some((&*x(), &*two())));
+
+        assert_eq!(Divide(Some(x()), None).binary_operands(), None);
+        assert_eq!(Sum(vec![*x(), *two()]).binary_operands(), None);
+        assert_eq!(Value(1.0).binary_operands(), None);
+    }
+
+    #[test]
+    fn test_piecewise() {
+        let x = || Text("x".to_string());
+        // x - 2 if x - 2 is nonzero, otherwise 10
+        let a: Operation = Piecewise(
+            vec![(Sum(vec![x(), Value(-2.0)]), Multiply(vec![Value(3.0), x()]))],
+            Some(Box::new(Value(10.0))),
+        );
+        let at = |x: f64| a.evaluate(&HashMap::from([("x".to_string(), x)])).unwrap();
+        assert_eq!(at(5.0), 15.0);
+        assert_eq!(at(2.0), 10.0);
+        assert_eq!(a.get_variables(), vec![x()]);
+        assert_eq!(a.equation_repr(), "{3 * x if x + -2, 10 otherwise}");
+        assert_eq!(
+            a.latex_string(),
+            "\\begin{cases}3$x$ & {$x$ + -2}\\\\10 & \\text{otherwise}\\end{cases}"
+        );
+        assert_eq!(Operation::from_sexpr(&a.to_sexpr()), Ok(a.clone()));
+
+        // value() uses the bound values of the symbols
+        let b: Operation = Piecewise(
+            vec![
+                (Value(0.0), Value(1.0)),
+                (Operation::variable_with_value("y", 1.0), Value(2.0)),
+            ],
+            None,
+        );
+        assert_eq!(b.value(), 2.0);
+        assert!(Piecewise(vec![(Value(0.0), Value(1.0))], None)
+            .value()
+            .is_nan());
+
+        // Constant conditions are folded
+        let c: Operation = Piecewise(
+            vec![
+                (Value(0.0), Value(1.0)),
+                (x(), Value(2.0)),
+                (Multiply(vec![Value(2.0), Value(3.0)]), Value(3.0)),
+                (x(), Value(4.0)),
+            ],
+            Some(Box::new(Value(5.0))),
+        );
+        assert_eq!(
+            c.simplify(),
+            Some(Piecewise(
+                vec![(x(), Value(2.0))],
+                Some(Box::new(Value(3.0)))
+            ))
+        );
+        let d: Operation = Piecewise(vec![(Value(1.0), x())], None);
+        assert_eq!(d.simplify(), Some(x()));
+
+        // An Equal condition holds when its sides are equal
+        let equal = |a: Operation, b: Operation| Equal(Some(Box::new(a)), Some(Box::new(b)));
+        let e: Operation = Piecewise(
+            vec![(equal(x(), Value(2.0)), Value(1.0))],
+            Some(Box::new(Value(0.0))),
+        );
+        let at = |x: f64| e.evaluate(&HashMap::from([("x".to_string(), x)])).unwrap();
+        assert_eq!(at(2.0), 1.0);
+        assert_eq!(at(5.0), 0.0);
+        let bound = |x: f64| {
+            Piecewise(
+                vec![(
+                    equal(Operation::variable_with_value("x", x), Value(2.0)),
+                    Value(1.0),
+                )],
+                Some(Box::new(Value(0.0))),
+            )
+        };
+        assert_eq!(bound(2.0).value(), 1.0);
+        assert_eq!(bound(5.0).value(), 0.0);
+        let f: Operation = Piecewise(
+            vec![
+                (equal(Value(1.0), Value(2.0)), Value(1.0)),
+                (equal(Value(2.0), Value(2.0)), Value(2.0)),
+            ],
+            Some(Box::new(Value(3.0))),
+        );
+        assert_eq!(f.simplify(), Some(Value(2.0)));
+    }
+
+    #[test]
+    fn test_is_simplified() {
+        let x = || Text("x".to_string());
+        assert!(x().is_simplified());
+        assert!(Value(2.0).is_simplified());
+        assert!(Multiply(vec![Value(2.0), x()]).is_simplified());
+        assert!(Sum(vec![x(), Operation::variable("y")]).is_simplified());
+
+        assert!(!Sum(vec![x(), Value(1.0), Value(2.0)]).is_simplified());
+        assert!(!Sum(vec![x(), Value(0.0)]).is_simplified());
+        assert!(!Multiply(vec![x(), Value(1.0)]).is_simplified());
+        assert!(!Multiply(vec![x(), Multiply(vec![x(), Value(2.0)])]).is_simplified());
+        assert!(!Negate(Some(Box::new(Negate(Some(Box::new(x())))))).is_simplified());
+        let a: Operation = Power(Some(Box::new(x())), Some(Box::new(Value(1.0))));
+        assert!(!a.is_simplified());
+    }
+
+    #[test]
+    fn test_normalize_equal() {
+        let x = || Text("x".to_string());
+        let a: Operation = Equal(
+            Some(Box::new(Sum(vec![x(), Value(1.0)]))),
+            Some(Box::new(Value(3.0))),
+        );
+        let normalized: Operation = a.normalize_equal().unwrap();
+        assert_eq!(normalized.equation_repr(), "x + -2 = 0");
+        if let Equal(Some(left), Some(right)) = &normalized {
+            assert_eq!(**left, Sum(vec![x(), Value(-2.0)]));
+            assert_eq!(**right, Value(0.0));
+        } else {
+            panic!("Expected an Equal");
+        }
+
+        // The default simplify keeps both sides
+        assert_eq!(a.simplify().unwrap().equation_repr(), "x + 1 = 3");
+        assert_eq!(x().normalize_equal(), None);
+    }
+
+    #[test]
+    fn test_sorted_variable_names() {
+        let t = |x: &str| Text(x.to_string());
+        let a: Operation = Sum(vec![
+            Multiply(vec![Value(2.0), t("y")]),
+            t("x"),
+            Operation::variable("b"),
+            t("y"),
+        ]);
+        let b: Operation = Sum(vec![
+            t("x"),
+            Operation::variable("b"),
+            Multiply(vec![t("y"), Value(2.0)]),
+            t("y"),
+        ]);
+        let names: Vec<String> = a.sorted_variable_names();
+        assert_eq!(names, vec!["b", "x", "y"]);
+        assert_eq!(b.sorted_variable_names(), names);
+        assert_eq!(
+            a.linear_coefficients(&names),
+            Some((vec![1.0, 1.0, 3.0], 0.0))
+        );
+    }
+
+    #[test]
+    fn test_evaluate_complex() {
+        let t = |x: &str| Text(x.to_string());
+        // Impedance of a capacitor, 1 / (j * omega * C)
+        let a: Operation = Divide(
+            Some(Box::new(Value(1.0))),
+            Some(Box::new(Multiply(vec![t("j"), t("omega"), t("C")]))),
+        );
+        let bindings: HashMap<String, Complex<f64>> = HashMap::from([
+            ("omega".to_string(), Complex::new(100.0, 0.0)),
+            ("C".to_string(), Complex::new(0.001, 0.0)),
+        ]);
+        let z: Complex<f64> = a.evaluate_complex(&bindings).unwrap();
+        assert!((z - Complex::new(0.0, -10.0)).norm() < 1e-12);
+
+        // R + j * omega * L
+        let a: Operation = Sum(vec![
+            t("R"),
+            Multiply(vec![
+                t("j"),
+                t("omega"),
+                Operation::variable_with_value("L", 0.5),
+            ]),
+        ]);
+        let mut bindings = bindings;
+        bindings.insert("R".to_string(), Complex::new(50.0, 0.0));
+        assert_eq!(a.evaluate_complex(&bindings), Ok(Complex::new(50.0, 50.0)));
+
+        let a: Operation = Power(Some(Box::new(t("i"))), Some(Box::new(Value(2.0))));
+        assert_eq!(
+            a.evaluate_complex(&HashMap::new()),
+            Ok(Complex::new(-1.0, 0.0))
+        );
+        assert!(t("x").evaluate_complex(&HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_distribute_negations() {
+        let t = |x: &str| Text(x.to_string());
+        let negate = |x: Operation| Negate(Some(Box::new(x)));
+        // a - (b - c)
+        let a: Operation = Sum(vec![t("a"), negate(Sum(vec![t("b"), negate(t("c"))]))]);
+        assert_eq!(a.latex_string(), "{$a$ - ({$b$ - $c$})}");
+        let b: Operation = a.distribute_negations();
+        assert_eq!(b, Sum(vec![t("a"), negate(t("b")), t("c")]));
+        assert_eq!(b.latex_string(), "{$a$ - $b$ + $c$}");
+        assert_eq!(a.value(), b.value());
+
+        // -(-(x))
+        assert_eq!(negate(negate(t("x"))).distribute_negations(), t("x"));
+    }
+
+    #[test]
+    fn test_multiply_zero() {
+        let x = || Text("x".to_string());
+        let a: Operation = Multiply(vec![Value(0.0), x()]);
+        assert_eq!(a.simplify(), Some(Value(0.0)));
+        let a: Operation = Multiply(vec![x(), Value(0.0), Text("y".to_string())]);
+        assert_eq!(a.simplify(), Some(Value(0.0)));
+        let a: Operation = Multiply(vec![x(), Sum(vec![Value(1.0), Value(-1.0)])]);
+        assert_eq!(a.simplify(), Some(Value(0.0)));
+
+        // 0 * inf is not zero
+        let a: Operation = Multiply(vec![Value(0.0), Value(f64::INFINITY), x()]);
+        assert_eq!(
+            a.simplify(),
+            Some(Multiply(vec![Value(0.0), Value(f64::INFINITY), x()]))
+        );
+    }
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn test_random_simplify_preserves_value() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut checked: usize = 0;
+        for _ in 0..2000 {
+            let a: Operation = Operation::random(&mut rng, 4);
+            if !a.get_variables().is_empty() || !a.value().is_finite() {
+                continue;
+            }
+            let b: Operation = a.simplify().unwrap_or_else(|| a.clone());
+            let (x, y) = (a.value(), b.value());
+            assert!(
+                (x - y).abs() <= 1e-9 * x.abs().max(1.0),
+                "{} = {} but simplified {} = {}",
+                a.equation_repr(),
+                x,
+                b.equation_repr(),
+                y
+            );
+            checked += 1;
+        }
+        assert!(checked > 100);
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let t = |x: &str| Text(x.to_string());
+        let boxed = |x: Operation| Some(Box::new(x));
+        // a / b = a * b^-1
+        let rules: Vec<RewriteRule> = vec![RewriteRule::new(
+            Divide(boxed(Mapping(0)), boxed(Mapping(1))),
+            Multiply(vec![
+                Mapping(0),
+                Power(boxed(Mapping(1)), boxed(Value(-1.0))),
+            ]),
+        )];
+        let a: Operation = Sum(vec![
+            Divide(boxed(t("a")), boxed(Sum(vec![t("b"), t("c")]))),
+            Negate(boxed(Divide(
+                boxed(Divide(boxed(t("x")), boxed(t("y")))),
+                boxed(Value(2.0)),
+            ))),
+        ]);
+        let inverse = |x: Operation| Power(boxed(x), boxed(Value(-1.0)));
+        assert_eq!(
+            a.rewrite(&rules),
+            Sum(vec![
+                Multiply(vec![t("a"), inverse(Sum(vec![t("b"), t("c")]))]),
+                Negate(boxed(Multiply(vec![
+                    Multiply(vec![t("x"), inverse(t("y"))]),
+                    inverse(Value(2.0)),
+                ]))),
+            ])
+        );
+        assert_eq!(t("x").rewrite(&rules), t("x"));
+    }
+
+    #[test]
+    fn test_reciprocal() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let x: Operation = Text("x".to_string());
+        assert_eq!(Value(4.0).reciprocal(), Value(0.25));
+        assert_eq!(Operation::rational(-2, 3).reciprocal(), Rational(-3, 2));
+        assert_eq!(
+            Value(0.0).reciprocal(),
+            Divide(boxed(Value(1.0)), boxed(Value(0.0)))
+        );
+        assert_eq!(
+            Divide(boxed(x.clone()), boxed(Value(2.0))).reciprocal(),
+            Divide(boxed(Value(2.0)), boxed(x.clone()))
+        );
+        assert_eq!(x.reciprocal(), Divide(boxed(Value(1.0)), boxed(x.clone())));
+        let sum: Operation = Sum(vec![x.clone(), Value(1.0)]);
+        assert_eq!(sum.reciprocal().reciprocal(), sum);
+    }
+
+    #[test]
+    fn test_is_template() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let template: Operation = Sum(vec![
+            Text("x".to_string()),
+            Negate(boxed(Power(boxed(Mapping(1)), boxed(Value(2.0))))),
+        ]);
+        assert!(Mapping(0).is_template());
+        assert!(template.is_template());
+        assert!(!Text("x".to_string()).is_template());
+        let filled: Operation = template.fill_mappings(&[Value(1.0), Value(3.0)]);
+        assert!(!filled.is_template());
+        assert_eq!(filled.value(), -8.0);
+        assert!(template.evaluate(&HashMap::new()).is_err());
+
+        // Templates have no coefficient or branch instead of panicking
+        assert_eq!(
+            Divide(boxed(Mapping(0)), boxed(Text("x".to_string()))).get_coefficient(),
+            None
+        );
+        let piecewise: Operation = Piecewise(vec![(Mapping(0), Value(1.0))], None);
+        assert_eq!(piecewise.piecewise_branch(), None);
+        assert!(piecewise.value().is_nan());
+    }
+
+    #[test]
+    #[should_panic(expected = "placeholder")]
+    fn test_mapping_value_panics() {
+        Sum(vec![Value(1.0), Mapping(0)]).value();
+    }
+
+    #[test]
+    fn test_latex_juxtaposition() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let x: Operation = Text("x".to_string());
+        assert_eq!(Multiply(vec![Value(2.0), x.clone()]).latex_string(), "2$x$");
+        assert_eq!(
+            Multiply(vec![Value(2.0), Value(3.0)]).latex_string(),
+            "2 \\cdot 3"
+        );
+        assert_eq!(
+            Multiply(vec![x.clone(), Value(2.0)]).latex_string(),
+            "$x$ \\cdot 2"
+        );
+        assert_eq!(
+            Multiply(vec![
+                Value(-4.0),
+                Power(boxed(x.clone()), boxed(Value(2.0))),
+                Sum(vec![x.clone(), Value(1.0)]),
+            ])
+            .latex_string(),
+            "-4$x$^{2} \\cdot ({$x$ + 1})"
+        );
+    }
+
+    #[test]
+    fn test_solve_quadratic() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let x: Operation = Text("x".to_string());
+        // x^2 - 5x + 6
+        let a: Operation = Sum(vec![
+            Power(boxed(x.clone()), boxed(Value(2.0))),
+            Negate(boxed(Multiply(vec![Value(5.0), x.clone()]))),
+            Value(6.0),
+        ]);
+        let roots: Vec<Operation> = a.solve_quadratic("x").unwrap();
+        let roots: Vec<Operation> = roots.iter().map(|x| x.simplify_fully()).collect();
+        assert_eq!(roots, vec![Value(3.0), Value(2.0)]);
+
+        // 2x(x + 1) = 4
+        let b: Operation = Equal(
+            boxed(Multiply(vec![
+                Value(2.0),
+                x.clone(),
+                Sum(vec![x.clone(), Value(1.0)]),
+            ])),
+            boxed(Value(4.0)),
+        );
+        let roots: Vec<f64> = b
+            .solve_quadratic("x")
+            .unwrap()
+            .iter()
+            .map(|x| x.value())
+            .collect();
+        assert_eq!(roots, vec![1.0, -2.0]);
+
+        let linear: Operation = Sum(vec![x.clone(), Value(1.0)]);
+        assert_eq!(linear.solve_quadratic("x"), None);
+        let cubic: Operation = Power(boxed(x.clone()), boxed(Value(3.0)));
+        assert_eq!(cubic.solve_quadratic("x"), None);
+        let symbolic: Operation = Multiply(vec![Text("a".to_string()), x.clone(), x.clone()]);
+        assert_eq!(symbolic.solve_quadratic("x"), None);
+    }
+
+    #[test]
+    fn test_sum_equation_repr_signs() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let t = |x: &str| Text(x.to_string());
+        let a: Operation = Sum(vec![
+            Negate(boxed(t("x"))),
+            t("y"),
+            Negate(boxed(Multiply(vec![Value(2.0), t("z")]))),
+        ]);
+        assert_eq!(a.equation_repr(), "-x + y - 2 * z");
+        let b: Operation = Sum(vec![
+            t("x"),
+            Negate(boxed(Sum(vec![t("y"), Negate(boxed(t("z")))]))),
+        ]);
+        assert_eq!(b.equation_repr(), "x - {y - z}");
+        assert_eq!(Sum(vec![Negate(boxed(t("x")))]).equation_repr(), "-x");
+        assert_eq!(
+            Sum(vec![t("x"), Negate(boxed(Negate(boxed(t("y")))))]).equation_repr(),
+            "x + y"
+        );
+    }
+
+    #[test]
+    fn test_flop_count() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let t = |x: &str| Text(x.to_string());
+        // (2 * x * y + -z) / x^2
+        let a: Operation = Divide(
+            boxed(Sum(vec![
+                Multiply(vec![Value(2.0), t("x"), t("y")]),
+                Negate(boxed(t("z"))),
+            ])),
+            boxed(Power(boxed(t("x")), boxed(Value(2.0)))),
+        );
+        assert_eq!(a.flop_count(), 6);
+        assert_eq!(t("x").flop_count(), 0);
+        assert_eq!(Sum(vec![]).flop_count(), 0);
+        assert_eq!(Operation::rational(1, 3).flop_count(), 1);
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::math::EquationMember;
-    use crate::prelude::*;
 
     #[test]
-    fn test_multiplication_simplification() {
-        let a: Operation = Multiply(vec![Value(2.0), Value(3.0)]);
-        assert_eq!(a.simplify(), Some(Value(6.0)));
+    fn test_expand_definitions() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let t = |x: &str| Text(x.to_string());
+        let mut context: Context = Context::new();
+        // Z = R + 1/(jwC)
+        context.define(
+            "Z",
+            Sum(vec![
+                t("R"),
+                Divide(
+                    boxed(Value(1.0)),
+                    boxed(Multiply(vec![t("j"), t("w"), t("C")])),
+                ),
+            ]),
+        );
+        let v: Operation = Multiply(vec![t("I"), t("Z")]);
+        let expanded: Operation = v.expand_definitions(&context);
+        assert_eq!(
+            expanded,
+            Multiply(vec![t("I"), context.get("Z").unwrap().clone()])
+        );
+        let bindings: HashMap<String, Complex<f64>> = ["I", "R", "w", "C"]
+            .iter()
+            .map(|x| (x.to_string(), Complex::new(1.0, 0.0)))
+            .collect();
+        assert_eq!(
+            expanded.evaluate_complex(&bindings),
+            Ok(Complex::new(1.0, -1.0))
+        );
 
-        let a: Operation = Multiply(vec![Value(2.0), Value(3.0), Value(4.0)]);
-        assert_eq!(a.simplify(), Some(Value(24.0)));
+        // a = b + 1, b = 2 * a
+        context
+            .define("a", Sum(vec![t("b"), Value(1.0)]))
+            .define("b", Multiply(vec![Value(2.0), t("a")]));
+        assert_eq!(
+            t("a").expand_definitions(&context),
+            Sum(vec![Multiply(vec![Value(2.0), t("a")]), Value(1.0)])
+        );
+        context.define("x", t("x"));
+        assert_eq!(t("x").expand_definitions(&context), t("x"));
+    }
 
-        let a: Operation = Multiply(vec![Value(2.0), Value(3.0), Text("x".to_string())]);
+    #[test]
+    fn test_free_variables_after() {
+        let t = |x: &str| Text(x.to_string());
+        // V = I * R, R = R0 * (1 + a * T), I = V / R
+        let v: Operation = Multiply(vec![t("I"), t("R")]);
+        let mut substitutions: HashMap<String, Operation> = HashMap::new();
+        substitutions.insert(
+            "R".to_string(),
+            Multiply(vec![
+                t("R0"),
+                Sum(vec![Value(1.0), Multiply(vec![t("a"), t("T")])]),
+            ]),
+        );
         assert_eq!(
-            a.simplify(),
-            Some(Multiply(vec![Value(6.0), Text("x".to_string())]))
+            v.free_variables_after(&substitutions),
+            ["I", "R0", "T", "a"]
         );
+        substitutions.insert(
+            "I".to_string(),
+            Divide(Some(Box::new(t("V"))), Some(Box::new(t("R")))),
+        );
+        assert_eq!(
+            v.free_variables_after(&substitutions),
+            ["R", "R0", "T", "V", "a"]
+        );
+        assert_eq!(v.free_variables_after(&HashMap::new()), ["I", "R"]);
+    }
 
-        let a: Operation = Multiply(vec![
+    #[test]
+    fn test_latex_string_with() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let t = |x: &str| Text(x.to_string());
+        let a: Operation = Divide(boxed(Sum(vec![t("x"), Value(1.0)])), boxed(Value(2.0)));
+        let slash = LatexStyle {
+            fractions: FractionStyle::Slash,
+        };
+        assert_eq!(a.latex_string(), "\\frac{{$x$ + 1}}{2}");
+        assert_eq!(a.latex_string_with(LatexStyle::default()), a.latex_string());
+        assert_eq!(a.latex_string_with(slash), "({$x$ + 1})/2");
+        let b: Operation = Multiply(vec![
             Value(3.0),
-            Value(2.0),
-            Text("x".to_string()),
-            Text("y".to_string()),
+            Divide(boxed(t("x")), boxed(Multiply(vec![Value(2.0), t("y")]))),
         ]);
-        assert_eq!(
-            a.simplify(),
-            Some(Multiply(vec![
-                Value(6.0),
-                Text("x".to_string()),
-                Text("y".to_string())
-            ]))
+        assert_eq!(b.latex_string_with(slash), "3 \\cdot $x$/(2$y$)");
+        assert_eq!(Operation::rational(1, 3).latex_string_with(slash), "1/3");
+        // Slash fractions are parenthesized as the base of a power
+        let c: Operation = Power(
+            boxed(Divide(boxed(t("x")), boxed(t("y")))),
+            boxed(Value(2.0)),
         );
+        assert_eq!(c.latex_string_with(slash), "($x$/$y$)^{2}");
+        assert_eq!(c.latex_string(), "(\\frac{$x$}{$y$})^{2}");
+        let d: Operation = Power(boxed(Operation::rational(1, 3)), boxed(Value(2.0)));
+        assert_eq!(d.latex_string_with(slash), "(1/3)^{2}");
     }
 
     #[test]
-    fn test_negation_simplification() {
-        let a: Operation = Negate(Some(Box::new(Value(2.0))));
-        assert_eq!(a.simplify(), Some(Value(-2.0)));
+    fn test_to_decimal_string() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let a: Operation = Divide(boxed(Value(22.0)), boxed(Value(7.0)));
+        assert_eq!(a.to_decimal_string(3), Some("3.143".to_string()));
+        assert_eq!(a.to_decimal_string(0), Some("3".to_string()));
+        assert_eq!(
+            Operation::rational(-1, 8).to_decimal_string(4),
+            Some("-0.1250".to_string())
+        );
+        let x: Operation = Operation::variable_with_value("x", 2.0);
+        assert_eq!(
+            Sum(vec![x, Value(0.5)]).to_decimal_string(1),
+            Some("2.5".to_string())
+        );
+        let t: Operation = Text("t".to_string());
+        assert_eq!(Sum(vec![t.clone(), Value(0.5)]).to_decimal_string(1), None);
+        assert_eq!(
+            Divide(boxed(Value(1.0)), boxed(Value(0.0))).to_decimal_string(2),
+            None
+        );
+        assert_eq!(
+            Equal(boxed(Value(1.0)), boxed(Value(1.0))).to_decimal_string(2),
+            None
+        );
+    }
 
-        let a: Operation = Negate(Some(Box::new(Negate(Some(Box::new(Value(2.0)))))));
-        assert_eq!(a.simplify(), Some(Value(2.0)));
+    #[test]
+    fn test_integrate() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let x = || Text("x".to_string());
+        let power = |a: Operation, n: f64| Power(boxed(a), boxed(Value(n)));
+        // x^3 -> x^4 / 4
+        assert_eq!(
+            power(x(), 3.0).integrate("x"),
+            Some(Divide(boxed(power(x(), 4.0)), boxed(Value(4.0))))
+        );
+        // 5 -> 5x
+        assert_eq!(
+            Value(5.0).integrate("x"),
+            Some(Multiply(vec![Value(5.0), x()]))
+        );
+        // 3x^2 - 2x + y, checked against the derivative
+        let a: Operation = Sum(vec![
+            Multiply(vec![Value(3.0), power(x(), 2.0)]),
+            Negate(boxed(Multiply(vec![Value(2.0), x()]))),
+            Text("y".to_string()),
+        ]);
+        let integral: Operation = a.integrate("x").unwrap();
+        let derivative: Operation = integral.derivative("x").unwrap();
+        for at in [-2.0, 0.5, 3.0] {
+            let bindings: HashMap<String, f64> =
+                HashMap::from([("x".to_string(), at), ("y".to_string(), 1.5)]);
+            assert_eq!(derivative.evaluate(&bindings), a.evaluate(&bindings));
+        }
+        // 1/x and x^-1 integrate to ln(x)
+        assert_eq!(
+            Divide(boxed(Value(1.0)), boxed(x())).integrate("x"),
+            Some(Multiply(vec![Value(1.0), Log(boxed(x()))]))
+        );
+        assert_eq!(power(x(), -1.0).integrate("x"), Some(Log(boxed(x()))));
+        let b: Operation = Sum(vec![Divide(boxed(Value(3.0)), boxed(x())), power(x(), 2.0)]);
+        let derivative: Operation = b.integrate("x").unwrap().derivative("x").unwrap();
+        for at in [0.5, 2.0, 7.0] {
+            let bindings: HashMap<String, f64> = HashMap::from([("x".to_string(), at)]);
+            let error: f64 =
+                derivative.evaluate(&bindings).unwrap() - b.evaluate(&bindings).unwrap();
+            assert!(error.abs() < 1e-12);
+        }
+        assert_eq!(Multiply(vec![x(), x()]).integrate("x"), None);
+        // x^x is not a power of x even when x has a value
+        let x: Operation = Operation::variable_with_value("x", 2.0);
+        assert_eq!(Power(boxed(x.clone()), boxed(x)).integrate("x"), None);
+    }
 
-        let a: Operation = Negate(Some(Box::new(Multiply(vec![Value(2.0), Value(3.0)]))));
-        assert_eq!(a.simplify(), Some(Value(-6.0)));
+    #[test]
+    fn test_log() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let x = || Text("x".to_string());
+        let a: Operation = Log(boxed(Sum(vec![x(), Value(1.0)])));
+        assert_eq!(a.equation_repr(), "ln(x + 1)");
+        assert_eq!(a.latex_string(), "\\ln({$x$ + 1})");
+        assert_eq!(Operation::from_sexpr(&a.to_sexpr()), Ok(a.clone()));
+        let bindings: HashMap<String, f64> = HashMap::from([("x".to_string(), 1.0)]);
+        assert_eq!(a.evaluate(&bindings), Ok(2f64.ln()));
+        let complex: HashMap<String, Complex<f64>> =
+            HashMap::from([("x".to_string(), Complex::new(1.0, 0.0))]);
+        assert_eq!(
+            a.evaluate_complex(&complex),
+            Ok(Complex::new(2f64.ln(), 0.0))
+        );
+        // d/dx ln(x + 1) = 1 / (x + 1)
+        assert_eq!(a.derivative("x").unwrap().evaluate(&bindings), Ok(0.5));
 
-        let a: Operation = Negate(Some(Box::new(Multiply(vec![Value(2.0)]))));
-        assert_eq!(a.simplify(), Some(Value(-2.0)));
+        assert_eq!(Log(boxed(Value(1.0))).simplify(), Some(Value(0.0)));
+        assert_eq!(Log(boxed(Value(2.0))).simplify(), Some(Value(2f64.ln())));
+        assert_eq!(
+            Log(boxed(Value(2.0))).simplify_with(SimplifyMode::ExactOnly),
+            None
+        );
+        assert_eq!(Log(boxed(x())).simplify(), None);
+        assert_eq!(Log(boxed(Value(-1.0))).simplify(), None);
+        assert_eq!(
+            Log(None).validate(),
+            Err("Log is missing an operand".to_string())
+        );
     }
 
     #[test]
-    fn test_division_simplification() {
-        let a: Operation = Divide(Some(Box::new(Value(2.0))), Some(Box::new(Value(3.0))));
-        assert_eq!(a.simplify(), Some(Value(2.0 / 3.0)));
-
-        let a: Operation = Divide(
-            Some(Box::new(Text("x".to_string()))),
-            Some(Box::new(Value(3.0))),
+    fn test_over_denominator() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let t = |x: &str| Text(x.to_string());
+        let half: Operation = Divide(boxed(t("x")), boxed(Value(2.0)));
+        assert_eq!(
+            half.over_denominator(&Value(6.0)),
+            Some(Divide(
+                boxed(Multiply(vec![Value(3.0), t("x")])),
+                boxed(Value(6.0))
+            ))
         );
+        assert_eq!(half.over_denominator(&Value(3.0)), None);
+        assert_eq!(half.over_denominator(&Value(0.0)), None);
         assert_eq!(
-            a.simplify(),
+            Operation::rational(2, 3).over_denominator(&Value(9.0)),
+            Some(Divide(boxed(Value(6.0)), boxed(Value(9.0))))
+        );
+        assert_eq!(
+            t("y").over_denominator(&Value(4.0)),
             Some(Divide(
-                Some(Box::new(Text("x".to_string()))),
-                Some(Box::new(Value(3.0)))
+                boxed(Multiply(vec![Value(4.0), t("y")])),
+                boxed(Value(4.0))
             ))
         );
 
-        let a: Operation = Divide(
-            Some(Box::new(Value(2.0))),
-            Some(Box::new(Text("x".to_string()))),
+        // 1 / a over a * b is b / (a * b)
+        let ab: Operation = Multiply(vec![t("a"), t("b")]);
+        let a: Operation = Divide(boxed(Value(1.0)), boxed(t("a")));
+        assert_eq!(
+            a.over_denominator(&ab),
+            Some(Divide(boxed(t("b")), boxed(ab.clone())))
         );
+        assert_eq!(a.over_denominator(&t("b")), None);
+        let c: Operation = Divide(boxed(t("c")), boxed(t("a")));
+        let abc: Operation = Multiply(vec![t("a"), t("b"), t("c")]);
         assert_eq!(
-            a.simplify(),
+            c.over_denominator(&abc),
             Some(Divide(
-                Some(Box::new(Value(2.0))),
-                Some(Box::new(Text("x".to_string())))
+                boxed(Multiply(vec![t("b"), t("c"), t("c")])),
+                boxed(abc.clone())
             ))
         );
+    }
 
-        // There is no simplification for x/y thus return None
+    #[test]
+    fn test_match_pattern() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let t = |x: &str| Text(x.to_string());
+        let pattern: Operation =
+            Divide(boxed(Sum(vec![Mapping(0), Mapping(1)])), boxed(Mapping(2)));
+        let square: Operation = Power(boxed(t("x")), boxed(Value(2.0)));
         let a: Operation = Divide(
-            Some(Box::new(Text("x".to_string()))),
-            Some(Box::new(Text("y".to_string()))),
+            boxed(Sum(vec![square.clone(), Value(1.0)])),
+            boxed(Multiply(vec![Value(2.0), t("y")])),
         );
-        assert_eq!(a.simplify(), None);
+        assert_eq!(
+            a.match_pattern(&pattern),
+            Some(vec![square, Value(1.0), Multiply(vec![Value(2.0), t("y")])])
+        );
+        let b: Operation = Divide(boxed(t("x")), boxed(t("y")));
+        assert_eq!(b.match_pattern(&pattern), None);
 
-        let a: Operation = Divide(
-            Some(Box::new(Negate(Some(Box::new(Multiply(vec![
-                Value(2.0),
-                Value(3.0),
-            ])))))),
-            Some(Box::new(Text("x".to_string()))),
+        let twice: Operation = Multiply(vec![Mapping(0), Mapping(0)]);
+        assert_eq!(
+            Multiply(vec![t("x"), t("x")]).match_pattern(&twice),
+            Some(vec![t("x")])
         );
+        assert_eq!(Multiply(vec![t("x"), t("y")]).match_pattern(&twice), None);
+        assert_eq!(t("x").match_pattern(&Mapping(1)), None);
+    }
+
+    #[test]
+    fn test_simplify_power_of_product() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let t = |x: &str| Text(x.to_string());
+        let power = |a: Operation, n: f64| Power(boxed(a), boxed(Value(n)));
+        // (2x)^2 = 4x^2
         assert_eq!(
-            a.simplify(),
-            Some(Divide(
-                Some(Box::new(Value(-6.0))),
-                Some(Box::new(Text("x".to_string())))
-            ))
+            power(Multiply(vec![Value(2.0), t("x")]), 2.0).simplify(),
+            Some(Multiply(vec![Value(4.0), power(t("x"), 2.0)]))
+        );
+        // (x * y)^3 = x^3 * y^3
+        assert_eq!(
+            power(Multiply(vec![t("x"), t("y")]), 3.0).simplify(),
+            Some(Multiply(vec![power(t("x"), 3.0), power(t("y"), 3.0)]))
+        );
+        // (x / 3)^2 = x^2 / 9
+        assert_eq!(
+            power(Divide(boxed(t("x")), boxed(Value(3.0))), 2.0).simplify(),
+            Some(Divide(boxed(power(t("x"), 2.0)), boxed(Value(9.0))))
         );
+        // (x * y)^0.5 is kept, the factors could be negative
+        let root: Operation = power(Multiply(vec![t("x"), t("y")]), 0.5);
+        assert_eq!(root.simplify(), None);
+    }
 
+    #[test]
+    fn test_is_constant_in() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let t = |x: &str| Text(x.to_string());
         let a: Operation = Divide(
-            Some(Box::new(Negate(Some(Box::new(Multiply(vec![
-                Value(2.0),
-                Value(3.0),
-            ])))))),
-            Some(Box::new(Negate(Some(Box::new(Value(2.0)))))),
+            boxed(Multiply(vec![Value(2.0), t("y")])),
+            boxed(Power(boxed(Operation::variable("x")), boxed(t("n")))),
         );
-        assert_eq!(a.simplify(), Some(Value(3.0)));
+        assert!(!a.is_constant_in("x"));
+        assert!(!a.is_constant_in("y"));
+        assert!(!a.is_constant_in("n"));
+        assert!(a.is_constant_in("z"));
+        assert!(a.is_constant_in("2"));
+        assert!(Value(3.0).is_constant_in("x"));
+        assert!(!t("x").is_constant_in("x"));
+    }
 
-        let a: Operation = Divide(
-            Some(Box::new(Negate(Some(Box::new(Multiply(vec![
-                Text("x".to_string()),
-                Value(2.0),
-            ])))))),
-            Some(Box::new(Negate(Some(Box::new(Value(2.0)))))),
+    #[test]
+    fn test_canonicalize_signs() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let neg = |x: Operation| Negate(Some(Box::new(x)));
+        let t = |x: &str| Text(x.to_string());
+        assert_eq!(neg(neg(neg(t("x")))).canonicalize_signs(), neg(t("x")));
+        assert_eq!(neg(neg(t("x"))).canonicalize_signs(), t("x"));
+
+        // (-a) * (-b) - (-c) * d + (-e) / f
+        let a: Operation = Sum(vec![
+            Multiply(vec![neg(t("a")), neg(t("b"))]),
+            neg(Multiply(vec![neg(t("c")), t("d")])),
+            Divide(boxed(neg(t("e"))), boxed(t("f"))),
+        ]);
+        assert_eq!(
+            a.canonicalize_signs(),
+            Sum(vec![
+                Multiply(vec![t("a"), t("b")]),
+                Multiply(vec![t("c"), t("d")]),
+                neg(Divide(boxed(t("e")), boxed(t("f")))),
+            ])
         );
+        let bindings: HashMap<String, f64> = ["a", "b", "c", "d", "e", "f"]
+            .iter()
+            .zip(1..)
+            .map(|(x, i)| (x.to_string(), i as f64))
+            .collect();
         assert_eq!(
-            a.simplify(),
-            Some(Divide(
-                Some(Box::new(Negate(Some(Box::new(Multiply(vec![
-                    Text("x".to_string()),
-                    Value(2.0),
-                ])))))),
-                Some(Box::new(Value(-2.0)))
-            ),)
+            a.canonicalize_signs().evaluate(&bindings),
+            a.evaluate(&bindings)
         );
+
+        // (-x)^2 is not -(x^2)
+        let square: Operation = Power(boxed(neg(t("x"))), boxed(Value(2.0)));
+        assert_eq!(square.canonicalize_signs(), square);
     }
 
     #[test]
-    fn test_summation_simplification() {
-        let a: Operation = Sum(vec![Value(2.0), Value(3.0)]);
-        assert_eq!(a.simplify(), Some(Value(5.0)));
+    fn test_deconstruct_reconstruct() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let x = || Text("x".to_string());
+        let operations: Vec<Operation> = vec![
+            Multiply(vec![Value(2.0), x(), x()]),
+            Sum(vec![x(), Value(1.0)]),
+            Sum(vec![]),
+            Negate(boxed(x())),
+            Divide(boxed(x()), boxed(Value(2.0))),
+            Equal(boxed(x()), boxed(Value(2.0))),
+            Power(boxed(x()), boxed(Value(2.0))),
+            Piecewise(
+                vec![(x(), Value(1.0)), (Value(1.0), Value(2.0))],
+                boxed(x()),
+            ),
+            Piecewise(vec![(x(), Value(1.0))], None),
+            Value(2.0),
+            x(),
+            Mapping(3),
+            Operation::rational(1, 3),
+            Divide(None, boxed(x())),
+            Negate(None),
+        ];
+        for a in operations {
+            let (kind, children) = a.deconstruct();
+            if !matches!(kind, OpKind::Leaf(_)) {
+                assert_eq!(children.len(), a.children().len());
+            }
+            assert_eq!(Operation::reconstruct(kind, children), a);
+        }
+        let (kind, children) = Power(boxed(x()), boxed(Value(2.0))).deconstruct();
+        assert_eq!(kind, OpKind::Power);
+        assert_eq!(children, vec![x(), Value(2.0)]);
+        assert_eq!(Negate(None).deconstruct().0, OpKind::Leaf(Negate(None)));
+    }
 
-        let a: Operation = Sum(vec![Value(2.0), Value(3.0), Value(4.0)]);
-        assert_eq!(a.simplify(), Some(Value(9.0)));
+    #[test]
+    #[should_panic(expected = "Cannot build Divide from 1 children")]
+    fn test_reconstruct_wrong_arity() {
+        Operation::reconstruct(OpKind::Divide, vec![Value(1.0)]);
+    }
 
-        let a: Operation = Sum(vec![Value(2.0), Value(3.0), Text("x".to_string())]);
+    #[test]
+    fn test_classify_variables() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let t = |x: &str| Text(x.to_string());
+        // (v_1 - v_2) / R + C * v_1
+        let a: Operation = Sum(vec![
+            Divide(
+                boxed(Sum(vec![t("v_1"), Negate(boxed(t("v_2")))])),
+                boxed(t("R")),
+            ),
+            Multiply(vec![t("C"), t("v_1")]),
+        ]);
+        let params: HashSet<String> = ["R", "C", "L"].iter().map(|x| x.to_string()).collect();
         assert_eq!(
-            a.simplify(),
-            Some(Sum(vec![Value(5.0), Text("x".to_string())]))
+            a.classify_variables(&params),
+            (
+                vec!["C".to_string(), "R".to_string()],
+                vec!["v_1".to_string(), "v_2".to_string()]
+            )
+        );
+        assert_eq!(
+            Value(1.0).classify_variables(&params),
+            (Vec::new(), Vec::new())
         );
+    }
 
+    #[test]
+    fn test_as_polynomial() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let t = |x: &str| Text(x.to_string());
+        let power = |a: Operation, n: f64| Power(boxed(a), boxed(Value(n)));
+        // 2x^2 + 3 - x
         let a: Operation = Sum(vec![
-            Value(2.0),
+            Multiply(vec![Value(2.0), power(t("x"), 2.0)]),
             Value(3.0),
-            Text("x".to_string()),
-            Text("y".to_string()),
+            Negate(boxed(t("x"))),
         ]);
         assert_eq!(
-            a.simplify(),
-            Some(Sum(vec![
-                Value(5.0),
-                Text("x".to_string()),
-                Text("y".to_string())
-            ]))
+            a.as_polynomial("x"),
+            Some(vec![Value(3.0), Value(-1.0), Value(2.0)])
         );
+        // (x + a)^2 = x^2 + 2ax + a^2
+        let b: Operation = power(Sum(vec![t("x"), t("a")]), 2.0);
+        let coefficients: Vec<Operation> = b.as_polynomial("x").unwrap();
+        assert_eq!(coefficients.len(), 3);
+        assert_eq!(coefficients[2], Value(1.0));
+        let bindings: HashMap<String, f64> = HashMap::from([("a".to_string(), 3.0)]);
+        let values: Vec<f64> = coefficients
+            .iter()
+            .map(|x| x.evaluate(&bindings).unwrap())
+            .collect();
+        assert_eq!(values, vec![9.0, 6.0, 1.0]);
 
-        let a: Operation = Sum(vec![
-            Value(2.0),
-            Value(3.0),
-            Text("x".to_string()),
-            Text("y".to_string()),
-            Value(4.0),
-        ]);
+        let cancelled: Operation = Sum(vec![t("x"), Negate(boxed(t("x")))]);
+        assert_eq!(cancelled.as_polynomial("x"), Some(vec![Value(0.0)]));
+        assert_eq!(t("y").as_polynomial("x"), Some(vec![t("y")]));
+        let reciprocal: Operation = Divide(boxed(Value(1.0)), boxed(t("x")));
+        assert_eq!(reciprocal.as_polynomial("x"), None);
+        assert_eq!(power(t("x"), 0.5).as_polynomial("x"), None);
+
+        // Coefficients are collected as they are formed, and the degree is capped
+        let c: Operation = power(Sum(vec![t("x"), t("a")]), 16.0);
+        let coefficients: Vec<Operation> = c.as_polynomial("x").unwrap();
+        assert_eq!(coefficients.len(), 17);
         assert_eq!(
-            a.simplify(),
-            Some(Sum(vec![
-                Value(9.0),
-                Text("x".to_string()),
-                Text("y".to_string())
-            ]))
+            coefficients[1].evaluate(&bindings),
+            Ok(16.0 * 3f64.powi(15))
         );
+        let limit: f64 = MAX_POLYNOMIAL_DEGREE as f64;
+        assert!(power(t("x"), limit).as_polynomial("x").is_some());
+        assert_eq!(power(t("x"), limit + 1.0).as_polynomial("x"), None);
+        assert_eq!(power(t("x"), 1e300).as_polynomial("x"), None);
+    }
 
+    #[test]
+    fn test_polynomial_degree() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let t = |x: &str| Text(x.to_string());
+        let vars: Vec<String> = vec!["x".to_string(), "y".to_string()];
+        // x * y + x^3 / 2
         let a: Operation = Sum(vec![
-            Value(0.0),
-            Text("x".to_string()),
-            Text("y".to_string()),
+            Multiply(vec![t("x"), t("y")]),
+            Divide(
+                boxed(Power(boxed(t("x")), boxed(Value(3.0)))),
+                boxed(Value(2.0)),
+            ),
         ]);
+        assert_eq!(a.polynomial_degree(&vars), Some(3));
         assert_eq!(
-            a.simplify(),
-            Some(Sum(vec![Text("x".to_string()), Text("y".to_string())]))
+            Multiply(vec![t("x"), t("y")]).polynomial_degree(&vars),
+            Some(2)
+        );
+        // Other symbols are constants
+        assert_eq!(
+            Multiply(vec![t("a"), t("x")]).polynomial_degree(&vars),
+            Some(1)
         );
+        assert_eq!(t("a").polynomial_degree(&vars), Some(0));
+        let reciprocal: Operation = Divide(boxed(Value(1.0)), boxed(t("x")));
+        assert_eq!(reciprocal.polynomial_degree(&vars), None);
+        let root: Operation = Power(boxed(t("y")), boxed(Value(0.5)));
+        assert_eq!(root.polynomial_degree(&vars), None);
+    }
 
-        let a: Operation = Sum(vec![Value(0.0), Text("x".to_string())]);
-        assert_eq!(a.simplify(), Some(Text("x".to_string())));
+    #[test]
+    fn test_is_homogeneous() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let t = |x: &str| Text(x.to_string());
+        let vars: Vec<String> = vec!["x".to_string(), "y".to_string()];
+        // x^2 + 3xy - k * y^2
+        let a: Operation = Sum(vec![
+            Power(boxed(t("x")), boxed(Value(2.0))),
+            Multiply(vec![Value(3.0), t("x"), t("y")]),
+            Negate(boxed(Multiply(vec![
+                t("k"),
+                Power(boxed(t("y")), boxed(Value(2.0))),
+            ]))),
+        ]);
+        assert!(a.is_homogeneous(&vars, 2));
+        assert!(!a.is_homogeneous(&vars, 1));
+        // x / y has degree 0
+        assert!(Divide(boxed(t("x")), boxed(t("y"))).is_homogeneous(&vars, 0));
+        // x^2 + y is not homogeneous
+        let b: Operation = Sum(vec![Power(boxed(t("x")), boxed(Value(2.0))), t("y")]);
+        assert!(!b.is_homogeneous(&vars, 2));
+        assert!(!b.is_homogeneous(&vars, 1));
+        assert!(!Mapping(0).is_homogeneous(&vars, 0));
     }
 
     #[test]
-    fn test_get_coefficient() {
-        let a: Operation = Divide(
-            Some(Box::new(Text("x".to_string()))),
-            Some(Box::new(Value(3.0))),
+    fn test_validate() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let t = |x: &str| Text(x.to_string());
+        let valid: Operation = Equal(
+            boxed(Sum(vec![t("x"), Negate(boxed(Value(1.0)))])),
+            boxed(Divide(boxed(t("y")), boxed(Value(2.0)))),
         );
-        assert_eq!(a.get_coefficient(), Some(1.0 / 3.0));
+        assert_eq!(valid.validate(), Ok(()));
+        assert_eq!(Value(1.0).validate(), Ok(()));
 
-        let a: Operation = Divide(
-            Some(Box::new(Value(2.0))),
-            Some(Box::new(Text("x".to_string()))),
+        let nested: Operation = Sum(vec![Equal(boxed(t("a")), boxed(t("b"))), Value(1.0)]);
+        assert_eq!(
+            nested.validate(),
+            Err("Equal cannot be nested inside Sum".to_string())
+        );
+        let deep: Operation = Equal(
+            boxed(Negate(boxed(Equal(boxed(t("a")), boxed(t("b")))))),
+            boxed(Value(0.0)),
+        );
+        assert_eq!(
+            deep.validate(),
+            Err("Equal cannot be nested inside Negate".to_string())
+        );
+        let incomplete: Operation = Multiply(vec![t("x"), Divide(None, boxed(t("y")))]);
+        assert_eq!(
+            incomplete.validate(),
+            Err("Divide is missing an operand".to_string())
+        );
+        assert_eq!(
+            Power(boxed(t("x")), None).validate(),
+            Err("Power is missing an operand".to_string())
         );
-        assert_eq!(a.get_coefficient(), Some(2.0));
 
-        let a: Operation = Divide(
-            Some(Box::new(Negate(Some(Box::new(Multiply(vec![
-                Value(2.0),
-                Value(3.0),
-            ])))))),
-            Some(Box::new(Text("x".to_string()))),
+        // An Equal may be the condition of a Piecewise, but not one of its values
+        let condition = || Equal(boxed(t("x")), boxed(Value(2.0)));
+        let piecewise: Operation = Piecewise(vec![(condition(), Value(1.0))], boxed(Value(0.0)));
+        assert_eq!(piecewise.validate(), Ok(()));
+        assert_eq!(
+            Piecewise(vec![(t("x"), condition())], None).validate(),
+            Err("Equal cannot be nested inside Piecewise".to_string())
         );
-        assert_eq!(a.get_coefficient(), Some(-6.0));
+        assert_eq!(
+            Piecewise(vec![(Value(1.0), Value(1.0))], boxed(condition())).validate(),
+            Err("Equal cannot be nested inside Piecewise".to_string())
+        );
+    }
 
-        let a: Operation = Multiply(vec![Value(2.0), Value(3.0), Text("x".to_string())]);
-        assert_eq!(a.get_coefficient(), Some(6.0));
+    #[test]
+    fn test_simplification_error() {
+        let boxed = |x: Operation| Some(Box::new(x));
+        let t = |x: &str| Text(x.to_string());
+        let power = |a: Operation, n: f64| Power(boxed(a), boxed(Value(n)));
+        let operations: Vec<Operation> = vec![
+            Multiply(vec![Value(2.0), t("x"), Value(3.0), t("y")]),
+            Sum(vec![t("x"), Value(1.0), Negate(boxed(t("x"))), Value(2.0)]),
+            Divide(
+                boxed(Multiply(vec![Value(6.0), t("x")])),
+                boxed(Multiply(vec![Value(4.0), t("y")])),
+            ),
+            power(Multiply(vec![Value(2.0), t("x")]), 3.0),
+            power(power(t("x"), 2.0), 3.0),
+            Multiply(vec![power(t("x"), 2.0), power(t("x"), -1.0), t("y")]),
+            Negate(boxed(Divide(boxed(t("x")), boxed(Value(3.0))))),
+        ];
+        for (x, y) in [(1.5, -2.0), (-0.25, 4.0), (3.0, 0.5)] {
+            let bindings: HashMap<String, f64> =
+                HashMap::from([("x".to_string(), x), ("y".to_string(), y)]);
+            for a in &operations {
+                let error: f64 = simplification_error(a, &bindings);
+                assert!(error <= 1e-9, "{} changed by {}", a.equation_repr(), error);
+            }
+        }
+        let unbound: Operation = Sum(vec![t("z"), Value(1.0)]);
+        assert!(simplification_error(&unbound, &HashMap::new()).is_nan());
     }
 }